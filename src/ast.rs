@@ -1,29 +1,313 @@
-#[derive(Debug, Clone)]
+use std::fmt;
+use std::str::FromStr;
+
+/// Region of source covered by a node. Lines and columns are 1-based, and the
+/// end position points one past the last character.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start_line: usize,
+    pub start_col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+impl Span {
+    pub fn new(start_line: usize, start_col: usize, end_line: usize, end_col: usize) -> Self {
+        Span { start_line, start_col, end_line, end_col }
+    }
+
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start_line: self.start_line,
+            start_col: self.start_col,
+            end_line: other.end_line,
+            end_col: other.end_col,
+        }
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}-{}:{}", self.start_line, self.start_col, self.end_line, self.end_col)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.start_line, self.start_col)
+    }
+}
+
+/// A type written in the source, e.g. in `let x: i64` or `-> bool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Type {
+    I32,
+    I64,
+    F64,
+    Bool,
+    /// `()`, the result of a function that returns no value
+    Unit,
+}
+
+impl FromStr for Type {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Type, String> {
+        match name {
+            "i32" => Ok(Type::I32),
+            "i64" => Ok(Type::I64),
+            "f64" => Ok(Type::F64),
+            "bool" => Ok(Type::Bool),
+            "()" => Ok(Type::Unit),
+            _ => Err(format!("unknown type `{}`", name)),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::F64 => write!(f, "f64"),
+            Type::Bool => write!(f, "bool"),
+            Type::Unit => write!(f, "()"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Program {
     pub statements: Vec<Stmt>,
 }
 
-#[derive(Debug, Clone)]
-pub enum Expr {
-    Number(i32),
+impl Program {
+    /// Render the program back into source, one statement per line with
+    /// blocks indented by four spaces. Parentheses are added only where
+    /// precedence needs them, so parsing the result gives the same tree
+    /// (up to spans).
+    pub fn to_source(&self) -> String {
+        let mut out = String::new();
+        write_block_body(&mut out, &self.statements, 0);
+        out
+    }
+
+    /// The program as pretty-printed JSON, for external tools. Every node is
+    /// an object keyed by its variant name, with spans included.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("the AST always serializes")
+    }
+
+    /// Read back a program written by `to_json`.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<Program, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// Binary operators. Every backend matches on this exhaustively, so adding a
+/// variant is a compile error until each consumer handles it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Gt,
+    Lt,
+    Eq,
+    Neq,
+    Ge,
+    Le,
+    And,
+    Or,
+}
+
+impl BinOp {
+    pub const ALL: [BinOp; 13] = [
+        BinOp::Add,
+        BinOp::Sub,
+        BinOp::Mul,
+        BinOp::Div,
+        BinOp::Mod,
+        BinOp::Gt,
+        BinOp::Lt,
+        BinOp::Eq,
+        BinOp::Neq,
+        BinOp::Ge,
+        BinOp::Le,
+        BinOp::And,
+        BinOp::Or,
+    ];
+
+    /// Source spelling of the operator.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Gt => ">",
+            BinOp::Lt => "<",
+            BinOp::Eq => "==",
+            BinOp::Neq => "!=",
+            BinOp::Ge => ">=",
+            BinOp::Le => "<=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+        }
+    }
+
+    /// Binding power; higher binds tighter. Matches `TokenKind::precedence`
+    /// for the operator's token.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 5,
+            BinOp::Add | BinOp::Sub => 4,
+            BinOp::Gt | BinOp::Lt | BinOp::Eq | BinOp::Neq | BinOp::Ge | BinOp::Le => 3,
+            BinOp::And => 2,
+            BinOp::Or => 1,
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<BinOp> {
+        BinOp::ALL.into_iter().find(|op| op.symbol() == symbol)
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+/// Prefix operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UnaryOp {
+    /// `-x`
+    Neg,
+    /// `!x`
+    Not,
+}
+
+impl UnaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+/// A literal as written. Numbers are kept wider than any target type; the
+/// semantic pass decides their final type from context and range-checks them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Literal {
+    Int(i128),
+    Float(f64),
+    /// `true` or `false`
+    Bool(bool),
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Int(n) => write!(f, "{}", n),
+            // `{}` never uses exponent notation, so this lexes back as a float
+            Literal::Float(x) if x.fract() == 0.0 => write!(f, "{}.0", x),
+            Literal::Float(x) => write!(f, "{}", x),
+            Literal::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    /// Type of the value, filled in by the semantic pass. `None` before
+    /// analysis, or where the type could not be determined. For a literal this
+    /// is the type it was resolved to in context, e.g. i64 for both literals
+    /// in `let a: i64 = 3 * 1000000000;`.
+    pub ty: Option<Type>,
+}
+
+impl Expr {
+    /// An expression whose type is not known yet.
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span, ty: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExprKind {
+    Number(Literal),
     Identifier(String),
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
     Binary {
         left: Box<Expr>,
-        operator: String,
+        operator: BinOp,
         right: Box<Expr>,
     },
     Call {
         name: String,
         args: Vec<Expr>,
     },
+    /// (a, b, ...) with at least two elements. Tuples are not values yet;
+    /// they only appear as the initializer of a destructuring `let`.
+    Tuple(Vec<Expr>),
+    /// base.name. Parsed so tools can represent member access ahead of
+    /// structs; no later stage accepts it yet.
+    Field {
+        base: Box<Expr>,
+        name: String,
+    },
 }
 
-#[derive(Debug, Clone)]
-pub enum Stmt {
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StmtKind {
     /// let name: type = value;
+    /// `var_type` is `None` when the annotation is omitted; the semantic pass
+    /// fills it in from the initializer when it can.
     VarDecl {
         name: String,
-        var_type: String,
+        var_type: Option<Type>,
+        value: Expr,
+    },
+
+    /// let (a, b, ...) = value;
+    /// Every name is bound in the current scope.
+    VarDeclTuple {
+        names: Vec<String>,
         value: Expr,
     },
 
@@ -46,17 +330,302 @@ pub enum Stmt {
         body: Vec<Stmt>,
     },
 
+    /// do { body } while condition;
+    /// The body runs once before the condition is first checked.
+    DoWhile {
+        body: Vec<Stmt>,
+        condition: Expr,
+    },
+
     /// function definition: fn name(params) -> ret_type { body }
+    /// An unannotated parameter is an `i32`. `ret_type` is `None` without a
+    /// `-> type`; such a function returns an `i32`, and 0 if it falls off the end.
     Function {
         name: String,
-        params: Vec<(String, String)>, // (param_name, param_type)
-        ret_type: String,
+        params: Vec<(String, Type)>, // (param_name, param_type)
+        ret_type: Option<Type>,
         body: Vec<Stmt>,
     },
 
     /// return expr_opt;
     Return(Option<Expr>),
 
+    /// break; -- leaves the innermost loop
+    Break,
+
+    /// continue; -- jumps to the innermost loop's condition
+    Continue,
+
     /// expression statement (e.g., a call on its own)
     ExprStmt(Expr),
 }
+
+/// Read-only traversal of the AST. Every method defaults to walking the
+/// node's children, so an analysis overrides only the nodes it cares about
+/// and calls `walk_stmt`/`walk_expr` itself to keep descending.
+pub trait Visitor {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+/// Visit the statements and expressions directly inside `stmt`, in source order.
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Stmt) {
+    match &stmt.kind {
+        StmtKind::VarDecl { value, .. }
+        | StmtKind::VarDeclTuple { value, .. }
+        | StmtKind::Assignment { value, .. }
+        | StmtKind::Return(Some(value))
+        | StmtKind::ExprStmt(value) => visitor.visit_expr(value),
+        StmtKind::IfStmt { condition, then_branch, else_branch } => {
+            visitor.visit_expr(condition);
+            then_branch.iter().for_each(|s| visitor.visit_stmt(s));
+            else_branch.iter().flatten().for_each(|s| visitor.visit_stmt(s));
+        }
+        StmtKind::While { condition, body } => {
+            visitor.visit_expr(condition);
+            body.iter().for_each(|s| visitor.visit_stmt(s));
+        }
+        StmtKind::DoWhile { body, condition } => {
+            body.iter().for_each(|s| visitor.visit_stmt(s));
+            visitor.visit_expr(condition);
+        }
+        StmtKind::Function { body, .. } => body.iter().for_each(|s| visitor.visit_stmt(s)),
+        StmtKind::Return(None) | StmtKind::Break | StmtKind::Continue => {}
+    }
+}
+
+/// Visit the subexpressions directly inside `expr`, left to right.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match &expr.kind {
+        ExprKind::Number(_) | ExprKind::Identifier(_) => {}
+        ExprKind::Unary { operand, .. } | ExprKind::Field { base: operand, .. } => visitor.visit_expr(operand),
+        ExprKind::Binary { left, right, .. } => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        }
+        ExprKind::Call { args: exprs, .. } | ExprKind::Tuple(exprs) => exprs.iter().for_each(|e| visitor.visit_expr(e)),
+    }
+}
+
+fn write_block_body(out: &mut String, stmts: &[Stmt], indent: usize) {
+    for stmt in stmts {
+        out.push_str(&"    ".repeat(indent));
+        write_stmt(out, stmt, indent);
+        out.push('\n');
+    }
+}
+
+/// `{`, the statements one level deeper, and `}` at `indent`.
+fn write_block(out: &mut String, stmts: &[Stmt], indent: usize) {
+    if stmts.is_empty() {
+        out.push_str("{}");
+        return;
+    }
+    out.push_str("{\n");
+    write_block_body(out, stmts, indent + 1);
+    out.push_str(&"    ".repeat(indent));
+    out.push('}');
+}
+
+fn write_stmt(out: &mut String, stmt: &Stmt, indent: usize) {
+    match &stmt.kind {
+        StmtKind::VarDecl { name, var_type, value } => {
+            out.push_str("let ");
+            out.push_str(name);
+            if let Some(ty) = var_type {
+                out.push_str(&format!(": {}", ty));
+            }
+            out.push_str(&format!(" = {};", expr_source(value)));
+        }
+        StmtKind::VarDeclTuple { names, value } => {
+            out.push_str(&format!("let ({}) = {};", names.join(", "), expr_source(value)));
+        }
+        StmtKind::Assignment { name, value } => out.push_str(&format!("{} = {};", name, expr_source(value))),
+        StmtKind::IfStmt { condition, then_branch, else_branch } => {
+            out.push_str(&format!("if {} ", expr_source(condition)));
+            write_block(out, then_branch, indent);
+            match else_branch.as_deref() {
+                None => {}
+                // a lone nested `if` is how the parser represents `else if`
+                Some([nested @ Stmt { kind: StmtKind::IfStmt { .. }, .. }]) => {
+                    out.push_str(" else ");
+                    write_stmt(out, nested, indent);
+                }
+                Some(stmts) => {
+                    out.push_str(" else ");
+                    write_block(out, stmts, indent);
+                }
+            }
+        }
+        StmtKind::While { condition, body } => {
+            out.push_str(&format!("while {} ", expr_source(condition)));
+            write_block(out, body, indent);
+        }
+        StmtKind::DoWhile { body, condition } => {
+            out.push_str("do ");
+            write_block(out, body, indent);
+            out.push_str(&format!(" while {};", expr_source(condition)));
+        }
+        StmtKind::Function { name, params, ret_type, body } => {
+            let params: Vec<String> = params.iter().map(|(p, ty)| format!("{}: {}", p, ty)).collect();
+            out.push_str(&format!("fn {}({}) ", name, params.join(", ")));
+            if let Some(ty) = ret_type {
+                out.push_str(&format!("-> {} ", ty));
+            }
+            write_block(out, body, indent);
+        }
+        StmtKind::Return(Some(value)) => out.push_str(&format!("return {};", expr_source(value))),
+        StmtKind::Return(None) => out.push_str("return;"),
+        StmtKind::Break => out.push_str("break;"),
+        StmtKind::Continue => out.push_str("continue;"),
+        StmtKind::ExprStmt(expr) => out.push_str(&format!("{};", expr_source(expr))),
+    }
+}
+
+fn expr_source(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::Number(lit) => lit.to_string(),
+        ExprKind::Identifier(name) => name.clone(),
+        ExprKind::Unary { op, operand } => match operand.kind {
+            ExprKind::Binary { .. } => format!("{}({})", op, expr_source(operand)),
+            _ => format!("{}{}", op, expr_source(operand)),
+        },
+        ExprKind::Binary { left, operator, right } => {
+            // operators associate to the left, so only a right operand of
+            // equal precedence needs parentheses
+            let prec = operator.precedence();
+            let left = operand_source(left, |p| p < prec);
+            let right = operand_source(right, |p| p <= prec);
+            format!("{} {} {}", left, operator, right)
+        }
+        ExprKind::Call { name, args } => {
+            let args: Vec<String> = args.iter().map(expr_source).collect();
+            format!("{}({})", name, args.join(", "))
+        }
+        ExprKind::Tuple(elems) => {
+            let elems: Vec<String> = elems.iter().map(expr_source).collect();
+            format!("({})", elems.join(", "))
+        }
+        ExprKind::Field { base, name } => match base.kind {
+            ExprKind::Identifier(_) | ExprKind::Call { .. } | ExprKind::Tuple(_) | ExprKind::Field { .. } => {
+                format!("{}.{}", expr_source(base), name)
+            }
+            _ => format!("({}).{}", expr_source(base), name),
+        },
+    }
+}
+
+/// An operand of a binary operator, parenthesized if it is itself a binary
+/// expression whose precedence satisfies `needs_parens`.
+fn operand_source(expr: &Expr, needs_parens: impl Fn(u8) -> bool) -> String {
+    match &expr.kind {
+        ExprKind::Binary { operator, .. } if needs_parens(operator.precedence()) => format!("({})", expr_source(expr)),
+        _ => expr_source(expr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn parse(src: &str) -> Program {
+        Parser::new(Lexer::new(src.to_string()).tokenize().unwrap()).parse().unwrap()
+    }
+
+    #[test]
+    fn float_literals_print_as_plain_decimals() {
+        assert_eq!(Literal::Float(1e20).to_string(), "100000000000000000000.0");
+        assert_eq!(Literal::Float(2.0).to_string(), "2.0");
+        assert_eq!(Literal::Float(0.125).to_string(), "0.125");
+        let program = parse("let x = 100000000000000000000.0 * 0.5;");
+        assert_eq!(program.to_source(), "let x = 100000000000000000000.0 * 0.5;\n");
+        assert_eq!(parse(&program.to_source()).to_source(), program.to_source());
+    }
+
+    #[test]
+    fn every_binary_operator_round_trips_through_the_parser() {
+        for op in BinOp::ALL {
+            assert_eq!(BinOp::from_symbol(op.symbol()), Some(op));
+            let program = parse(&format!("let x = a {} b;", op));
+            match &program.statements[0].kind {
+                StmtKind::VarDecl { value: Expr { kind: ExprKind::Binary { operator, .. }, .. }, .. } => {
+                    assert_eq!(*operator, op)
+                }
+                other => panic!("expected a binary let, found {:?}", other),
+            }
+            assert_eq!(program.to_source(), format!("let x = a {} b;\n", op));
+        }
+    }
+
+    #[test]
+    fn visitor_reaches_every_nested_expression() {
+        struct Numbers(usize);
+        impl Visitor for Numbers {
+            fn visit_expr(&mut self, expr: &Expr) {
+                if let ExprKind::Number(_) = expr.kind {
+                    self.0 += 1;
+                }
+                walk_expr(self, expr);
+            }
+        }
+        let program = parse(
+            "fn f(a: i32) -> i32 { return a + 1; } let x = f(2) * 3;
+             if x > 4 { x = -5; } else { x = 6; } do { x = x - 7; } while x > 8;",
+        );
+        let mut numbers = Numbers(0);
+        for stmt in &program.statements {
+            numbers.visit_stmt(stmt);
+        }
+        assert_eq!(numbers.0, 8);
+    }
+
+    #[test]
+    fn printed_source_parses_back_to_the_same_tree() {
+        use crate::utils::ast_diff;
+        let demo = "
+            fn add(a: i32, b: i32) -> i32 {
+                return a + b;
+            }
+            let x: i32 = 5;
+            let y: i32 = 10;
+            let z: i32 = add(x, y);
+            if z > 10 {
+                z = z + 1;
+            } else {
+                z = z - 1;
+            }
+            let i: i32 = 0;
+        ";
+        let everything = "let (a, b) = (1, 2 * 3); a = (a - b) - (b - a); a = a - (b - a) * 2 / (1 + b) % 3; x = -(a + 1) * -3;
+            x = !(a > 1 && b < 2) || a == b; if a { } else if b { return; } else { x = 1.5; }
+            while a < 3 { a = a + 1; if a == 2 { continue; } break; } do { f(); } while 1;
+            fn g() -> () { } fn h(p: i64, q: bool) -> bool { return p.q; } y = (a + b).c;";
+        for src in [demo, everything] {
+            let program = parse(src);
+            let printed = program.to_source();
+            let reparsed = parse(&printed);
+            assert_eq!(ast_diff(&program, &reparsed), None, "{}", printed);
+            assert_eq!(reparsed.to_source(), printed);
+        }
+        assert!(parse(demo).to_source().starts_with("fn add(a: i32, b: i32) -> i32 {\n    return a + b;\n}\nlet x: i32 = 5;\n"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trips_every_node_kind() {
+        let program = parse(
+            "fn add(a: i32, b: i32) -> i32 { return a + b; } let x: i32 = 5; let z: i32 = add(x, 2);
+             if z > 10 { z = z + 1; } else { z = -z; } let f = 1.5; do { break; } while !(z == 1);
+             let (q, r) = (1, 2); a.b;",
+        );
+        assert_eq!(Program::from_json(&program.to_json()).unwrap(), program);
+        assert!(Program::from_json("{}").is_err());
+    }
+}
@@ -1,33 +1,234 @@
 // codegen_bytecode.rs
 use std::collections::HashMap;
-use crate::ast::{Program, Stmt, Expr};
+use std::fmt;
+use crate::ast::{BinOp, Literal, Program, Stmt, StmtKind, Expr, ExprKind, Span, Type, UnaryOp};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instr {
     PushInt(i32),
+    PushFloat(f64),
+    PushBool(bool),
     Load(String),       // push variable value
     Store(String),      // pop and store into variable
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    Neg,                // pop an int or float; push its negation
+    Not,                // pop a value; push true if it is falsy
     Gt,
     Lt,
     Eq,
     Neq,
+    Ge,
+    Le,
+    And,                // pop two values; push their logical and (by truthiness)
+    Or,
     Jump(usize),        // unconditional jump to instruction index
     JumpIfFalse(usize), // pop value; if false (0) jump
+    // Functions are called by entry address, not by name: the emitter resolves
+    // names and checks argument counts, and the VM checks the count again
+    // against the callee's `Enter`.
+    Call(usize, usize), // push return address and jump to a function's entry, passing this many arguments
+    Enter(usize),       // first instruction of a function: its parameter count; then Stores of the arguments
+    Ret,                // return to the caller, leaving the result on the stack
     Pop,
     Halt,
 }
 
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instr::PushInt(n) => write!(f, "PushInt {}", n),
+            Instr::PushFloat(x) => write!(f, "PushFloat {:?}", x),
+            Instr::PushBool(b) => write!(f, "PushBool {}", b),
+            Instr::Load(name) => write!(f, "Load {}", name),
+            Instr::Store(name) => write!(f, "Store {}", name),
+            Instr::Jump(addr) => write!(f, "Jump {}", addr),
+            Instr::JumpIfFalse(addr) => write!(f, "JumpIfFalse {}", addr),
+            Instr::Call(addr, argc) => write!(f, "Call {} {}", addr, argc),
+            Instr::Enter(arity) => write!(f, "Enter {}", arity),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+/// Parse textual bytecode, one instruction per line in the form printed by
+/// `Instr`'s `Display` (e.g. `PushInt 5`, `JumpIfFalse 7`). Jump targets are
+/// absolute instruction indices. Blank lines and `;` comments are ignored, and
+/// so is a leading index, so the output of `disassemble` assembles too.
+pub fn assemble(text: &str) -> Result<Vec<Instr>, String> {
+    let mut code = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.split_whitespace().peekable();
+        parts.next_if(|word| word.bytes().all(|b| b.is_ascii_digit()));
+        let op = parts.next().unwrap_or("");
+        let operand = parts.next();
+        // `Call` is the only instruction with a second operand
+        let argc = if op == "Call" { parts.next() } else { None };
+        if parts.next().is_some() {
+            return Err(format!("line {}: too many operands in `{}`", i + 1, line));
+        }
+        let instr = match op {
+            "PushInt" => Instr::PushInt(parse_operand(i, op, operand, "an integer")?),
+            "PushFloat" => Instr::PushFloat(parse_operand(i, op, operand, "a float")?),
+            "PushBool" => Instr::PushBool(parse_operand(i, op, operand, "`true` or `false`")?),
+            "Load" => Instr::Load(parse_operand(i, op, operand, "a variable name")?),
+            "Store" => Instr::Store(parse_operand(i, op, operand, "a variable name")?),
+            "Jump" => Instr::Jump(parse_operand(i, op, operand, "a target index")?),
+            "JumpIfFalse" => Instr::JumpIfFalse(parse_operand(i, op, operand, "a target index")?),
+            "Call" => Instr::Call(
+                parse_operand(i, op, operand, "a target index")?,
+                parse_operand(i, op, argc, "an argument count")?,
+            ),
+            "Enter" => Instr::Enter(parse_operand(i, op, operand, "a parameter count")?),
+            _ => {
+                let instr = match op {
+                    "Add" => Instr::Add,
+                    "Sub" => Instr::Sub,
+                    "Mul" => Instr::Mul,
+                    "Div" => Instr::Div,
+                    "Mod" => Instr::Mod,
+                    "Neg" => Instr::Neg,
+                    "Not" => Instr::Not,
+                    "Gt" => Instr::Gt,
+                    "Lt" => Instr::Lt,
+                    "Eq" => Instr::Eq,
+                    "Neq" => Instr::Neq,
+                    "Ge" => Instr::Ge,
+                    "Le" => Instr::Le,
+                    "And" => Instr::And,
+                    "Or" => Instr::Or,
+                    "Ret" => Instr::Ret,
+                    "Pop" => Instr::Pop,
+                    "Halt" => Instr::Halt,
+                    _ => return Err(format!("line {}: unknown instruction `{}`", i + 1, op)),
+                };
+                if operand.is_some() {
+                    return Err(format!("line {}: {} takes no operand", i + 1, op));
+                }
+                instr
+            }
+        };
+        code.push(instr);
+    }
+    Ok(code)
+}
+
+/// Listing of `code` with one instruction per line as `Instr`'s `Display`
+/// prints it, prefixed by its index (`0012 JumpIfFalse 20`). `assemble`
+/// skips the index, so the listing assembles back to `code`.
+pub fn disassemble(code: &[Instr]) -> String {
+    let mut out = String::new();
+    for (i, instr) in code.iter().enumerate() {
+        out.push_str(&format!("{:04} {}\n", i, instr));
+    }
+    out
+}
+
+fn parse_operand<T: std::str::FromStr>(line: usize, op: &str, operand: Option<&str>, what: &str) -> Result<T, String> {
+    operand
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| format!("line {}: {} expects {}", line + 1, op, what))
+}
+
+/// A construct the bytecode backend cannot compile, with the span of the
+/// offending node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BytecodeError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl BytecodeError {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        BytecodeError { message: message.into(), span }
+    }
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
 pub struct Emitter {
     pub code: Vec<Instr>,
     // temporary stack for backpatch addresses, if needed
+    /// block scopes, innermost last: source name -> VM variable slot
+    scopes: Vec<HashMap<String, String>>,
+    slot_counter: usize,
+    /// entry address and parameter count of each function compiled so far
+    functions: HashMap<String, (usize, usize)>,
+    /// `Call`s emitted before their callee was compiled, as (index, callee,
+    /// argument count, span); patched by `compile_program` at the end
+    pending_calls: Vec<(usize, String, usize, Span)>,
+    /// enclosing loops, innermost last
+    loops: Vec<LoopJumps>,
+}
+
+/// The `break` and `continue` jumps of one loop, patched once the loop's
+/// exit and condition addresses are known.
+#[derive(Default)]
+struct LoopJumps {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Emitter {
-    pub fn new() -> Self { Emitter { code: vec![] } }
+    pub fn new() -> Self { Emitter {
+            code: vec![],
+            scopes: vec![HashMap::new()],
+            slot_counter: 0,
+            functions: HashMap::new(),
+            pending_calls: Vec::new(),
+            loops: Vec::new(),
+        } }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Bind `name` in the current scope and return its slot. Top-level names
+    /// keep their own name; block-local ones get a fresh slot so they neither
+    /// clobber an outer variable nor stay visible after the block.
+    pub fn declare(&mut self, name: &str) -> String {
+        let slot = if self.scopes.len() <= 1 {
+            name.to_string()
+        } else {
+            self.slot_counter += 1;
+            format!("{}#{}", name, self.slot_counter)
+        };
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), slot.clone());
+        }
+        slot
+    }
+
+    /// Slot for `name` from the innermost scope that declares it. Unknown names
+    /// map to themselves and fail at runtime if never stored.
+    pub fn resolve(&self, name: &str) -> String {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+            .unwrap_or_else(|| name.to_string())
+    }
 
     pub fn emit(&mut self, instr: Instr) {
         self.code.push(instr);
@@ -40,149 +241,1059 @@ impl Emitter {
     pub fn patch(&mut self, idx: usize, instr: Instr) {
         self.code[idx] = instr;
     }
+
+    /// Compile a loop body, then point its `continue`s at `continue_to`
+    /// (`None`: the next instruction) and return the `break` jumps, which
+    /// the caller patches to the loop's exit.
+    fn compile_loop_body(&mut self, body: &[Stmt], continue_to: Option<usize>) -> Result<Vec<usize>, BytecodeError> {
+        self.loops.push(LoopJumps::default());
+        self.push_scope();
+        let compiled = body.iter().try_for_each(|s| compile_stmt(self, s));
+        self.pop_scope();
+        let jumps = self.loops.pop().expect("pushed above");
+        compiled?;
+        let target = continue_to.unwrap_or(self.pc());
+        for pos in jumps.continues {
+            self.patch(pos, Instr::Jump(target));
+        }
+        Ok(jumps.breaks)
+    }
+
+    fn patch_jumps(&mut self, jumps: Vec<usize>, target: usize) {
+        for pos in jumps {
+            self.patch(pos, Instr::Jump(target));
+        }
+    }
+
+    /// Emit a binary instruction, folding it into a single push when both
+    /// operands were just pushed as integer constants (`PushInt 2; PushInt 3;
+    /// Add` becomes `PushInt 5`). Division or modulo by zero and overflowing
+    /// results are left for the VM to evaluate.
+    pub fn emit_binary(&mut self, instr: Instr) {
+        if let [.., Instr::PushInt(a), Instr::PushInt(b)] = self.code[..] {
+            let folded = match instr {
+                Instr::Add => a.checked_add(b).map(Instr::PushInt),
+                Instr::Sub => a.checked_sub(b).map(Instr::PushInt),
+                Instr::Mul => a.checked_mul(b).map(Instr::PushInt),
+                Instr::Div => a.checked_div(b).map(Instr::PushInt),
+                Instr::Mod => a.checked_rem(b).map(Instr::PushInt),
+                Instr::Gt => Some(Instr::PushBool(a > b)),
+                Instr::Lt => Some(Instr::PushBool(a < b)),
+                Instr::Eq => Some(Instr::PushBool(a == b)),
+                Instr::Neq => Some(Instr::PushBool(a != b)),
+                Instr::Ge => Some(Instr::PushBool(a >= b)),
+                Instr::Le => Some(Instr::PushBool(a <= b)),
+                _ => None,
+            };
+            if let Some(folded) = folded {
+                self.code.truncate(self.code.len() - 2);
+                self.code.push(folded);
+                return;
+            }
+        }
+        self.emit(instr);
+    }
 }
 
-pub fn compile_program(program: &Program) -> Vec<Instr> {
+pub fn compile_program(program: &Program) -> Result<Vec<Instr>, BytecodeError> {
     let mut e = Emitter::new();
     for s in &program.statements {
-        compile_stmt(&mut e, s);
+        compile_stmt(&mut e, s)?;
     }
     e.emit(Instr::Halt);
-    e.code
+    // calls that appeared above their callee's definition
+    for (pos, name, argc, span) in std::mem::take(&mut e.pending_calls) {
+        let addr = check_call(&e, &name, argc, span)?;
+        e.patch(pos, Instr::Call(addr, argc));
+    }
+    Ok(e.code)
 }
 
-fn compile_stmt(e: &mut Emitter, stmt: &Stmt) {
-    match stmt {
-        Stmt::VarDecl { name, var_type: _, value } => {
-            compile_expr(e, value);
-            e.emit(Instr::Store(name.clone()));
+/// Entry address of `name`, checking that it takes `argc` arguments.
+fn check_call(e: &Emitter, name: &str, argc: usize, span: Span) -> Result<usize, BytecodeError> {
+    let Some(&(addr, arity)) = e.functions.get(name) else {
+        return Err(BytecodeError::new(format!("call to unknown function `{}`", name), span));
+    };
+    if argc != arity {
+        let message = format!("`{}` takes {} argument(s) but {} were supplied", name, arity, argc);
+        return Err(BytecodeError::new(message, span));
+    }
+    Ok(addr)
+}
+
+fn compile_stmt(e: &mut Emitter, stmt: &Stmt) -> Result<(), BytecodeError> {
+    match &stmt.kind {
+        StmtKind::VarDecl { name, var_type: _, value } => {
+            compile_expr(e, value)?;
+            let slot = e.declare(name);
+            e.emit(Instr::Store(slot));
+        }
+        StmtKind::VarDeclTuple { names, value } => {
+            let ExprKind::Tuple(elems) = &value.kind else {
+                return Err(BytecodeError::new("destructuring `let` needs a tuple initialiser", value.span));
+            };
+            // evaluate every element before binding, so `let (a, b) = (b, a);` swaps
+            for elem in elems {
+                compile_expr(e, elem)?;
+            }
+            for name in names.iter().rev() {
+                let slot = e.declare(name);
+                e.emit(Instr::Store(slot));
+            }
         }
-        Stmt::Assignment { name, value } => {
-            compile_expr(e, value);
-            e.emit(Instr::Store(name.clone()));
+        StmtKind::Assignment { name, value } => {
+            compile_expr(e, value)?;
+            let slot = e.resolve(name);
+            e.emit(Instr::Store(slot));
         }
-        Stmt::IfStmt { condition, then_branch } => {
-            compile_expr(e, condition);
+        StmtKind::IfStmt { condition, then_branch, else_branch } => {
+            compile_expr(e, condition)?;
             // emit placeholder for JumpIfFalse, will patch after body
             let jmp_if_false_pos = e.pc();
             e.emit(Instr::JumpIfFalse(0)); // placeholder
+            e.push_scope();
             for s in then_branch {
-                compile_stmt(e, s);
+                compile_stmt(e, s)?;
             }
-            // patch to jump to next instruction after body
+            e.pop_scope();
+            match else_branch {
+                None => {
+                    // patch to jump to next instruction after body
+                    let after_body = e.pc();
+                    e.patch(jmp_if_false_pos, Instr::JumpIfFalse(after_body));
+                }
+                Some(else_branch) => {
+                    // the then-branch jumps over the else-branch when it finishes
+                    let skip_else_pos = e.pc();
+                    e.emit(Instr::Jump(0)); // placeholder
+                    let else_start = e.pc();
+                    e.patch(jmp_if_false_pos, Instr::JumpIfFalse(else_start));
+                    e.push_scope();
+                    for s in else_branch {
+                        compile_stmt(e, s)?;
+                    }
+                    e.pop_scope();
+                    let after_else = e.pc();
+                    e.patch(skip_else_pos, Instr::Jump(after_else));
+                }
+            }
+        }
+        StmtKind::While { condition, body } => {
+            let cond_start = e.pc();
+            compile_expr(e, condition)?;
+            // placeholder, patched to the first instruction after the loop
+            let exit_pos = e.pc();
+            e.emit(Instr::JumpIfFalse(0));
+            let breaks = e.compile_loop_body(body, Some(cond_start))?;
+            e.emit(Instr::Jump(cond_start));
+            let after_loop = e.pc();
+            e.patch(exit_pos, Instr::JumpIfFalse(after_loop));
+            e.patch_jumps(breaks, after_loop);
+        }
+        StmtKind::DoWhile { body, condition } => {
+            // the body runs before the first check; `continue` goes to the check
+            let body_start = e.pc();
+            let breaks = e.compile_loop_body(body, None)?;
+            compile_expr(e, condition)?;
+            let exit_pos = e.pc();
+            e.emit(Instr::JumpIfFalse(0)); // placeholder
+            e.emit(Instr::Jump(body_start));
+            let after_loop = e.pc();
+            e.patch(exit_pos, Instr::JumpIfFalse(after_loop));
+            e.patch_jumps(breaks, after_loop);
+        }
+        StmtKind::Break | StmtKind::Continue => {
+            let pos = e.pc();
+            let Some(jumps) = e.loops.last_mut() else {
+                return Err(BytecodeError::new("`break` or `continue` outside of a loop", stmt.span));
+            };
+            let pending = if matches!(stmt.kind, StmtKind::Break) { &mut jumps.breaks } else { &mut jumps.continues };
+            pending.push(pos);
+            e.emit(Instr::Jump(0)); // placeholder
+        }
+        StmtKind::Function { name, params, body, .. } => {
+            // the body is emitted inline; jump over it on the normal path
+            let skip_pos = e.pc();
+            e.emit(Instr::Jump(0)); // placeholder
+            e.functions.insert(name.clone(), (e.pc(), params.len()));
+            e.emit(Instr::Enter(params.len()));
+            // a loop around the definition is not one the body can leave
+            let enclosing_loops = std::mem::take(&mut e.loops);
+            e.push_scope();
+            // the caller pushed the arguments in order, so the last one is on top
+            for (param, _) in params.iter().rev() {
+                let slot = e.declare(param);
+                e.emit(Instr::Store(slot));
+            }
+            let compiled = body.iter().try_for_each(|s| compile_stmt(e, s));
+            e.pop_scope();
+            e.loops = enclosing_loops;
+            compiled?;
+            // falling off the end returns 0, like the LLVM backend
+            e.emit(Instr::PushInt(0));
+            e.emit(Instr::Ret);
             let after_body = e.pc();
-            e.patch(jmp_if_false_pos, Instr::JumpIfFalse(after_body));
-        }
-    }
-}
-
-fn compile_expr(e: &mut Emitter, expr: &Expr) {
-    match expr {
-        Expr::Number(n) => e.emit(Instr::PushInt(*n)),
-        Expr::Identifier(name) => e.emit(Instr::Load(name.clone())),
-        Expr::Binary { left, operator, right } => {
-            compile_expr(e, left);
-            compile_expr(e, right);
-            match operator.as_str() {
-                "+" => e.emit(Instr::Add),
-                "-" => e.emit(Instr::Sub),
-                "*" => e.emit(Instr::Mul),
-                "/" => e.emit(Instr::Div),
-                ">" => e.emit(Instr::Gt),
-                "<" => e.emit(Instr::Lt),
-                "==" => e.emit(Instr::Eq),
-                "!=" => e.emit(Instr::Neq),
-                _ => panic!("Unknown operator {}", operator),
+            e.patch(skip_pos, Instr::Jump(after_body));
+        }
+        StmtKind::Return(value) => {
+            match value {
+                Some(value) => compile_expr(e, value)?,
+                None => e.emit(Instr::PushInt(0)),
+            }
+            e.emit(Instr::Ret);
+        }
+        StmtKind::ExprStmt(expr) => {
+            compile_expr(e, expr)?;
+            e.emit(Instr::Pop);
+        }
+    }
+    Ok(())
+}
+
+fn compile_expr(e: &mut Emitter, expr: &Expr) -> Result<(), BytecodeError> {
+    match &expr.kind {
+        ExprKind::Number(lit) => match lit {
+            // the analyzer resolved the literal to a float, as in `let h: f64 = 7 / 2;`
+            Literal::Int(n) if expr.ty == Some(Type::F64) => e.emit(Instr::PushFloat(*n as f64)),
+            Literal::Int(n) if expr.ty == Some(Type::I64) => {
+                let message = format!("the bytecode backend does not support i64 values yet, found {} used as i64", n);
+                return Err(BytecodeError::new(message, expr.span));
+            }
+            Literal::Int(n) => match i32::try_from(*n) {
+                Ok(n) => e.emit(Instr::PushInt(n)),
+                Err(_) => {
+                    let message = format!("the bytecode backend only supports i32 literals yet, found {}", n);
+                    return Err(BytecodeError::new(message, expr.span));
+                }
+            },
+            Literal::Float(x) => e.emit(Instr::PushFloat(*x)),
+            Literal::Bool(b) => e.emit(Instr::PushBool(*b)),
+        },
+        ExprKind::Identifier(name) => {
+            let slot = e.resolve(name);
+            e.emit(Instr::Load(slot));
+        }
+        ExprKind::Unary { op, operand } => {
+            compile_expr(e, operand)?;
+            e.emit(match op {
+                UnaryOp::Neg => Instr::Neg,
+                UnaryOp::Not => Instr::Not,
+            });
+        }
+        ExprKind::Binary { left, operator: op @ (BinOp::And | BinOp::Or), right } => {
+            compile_logical(e, left, *op, right)?;
+        }
+        ExprKind::Binary { left, operator, right } => {
+            compile_expr(e, left)?;
+            compile_expr(e, right)?;
+            e.emit_binary(match operator {
+                BinOp::Add => Instr::Add,
+                BinOp::Sub => Instr::Sub,
+                BinOp::Mul => Instr::Mul,
+                BinOp::Div => Instr::Div,
+                BinOp::Mod => Instr::Mod,
+                BinOp::Gt => Instr::Gt,
+                BinOp::Lt => Instr::Lt,
+                BinOp::Eq => Instr::Eq,
+                BinOp::Neq => Instr::Neq,
+                BinOp::Ge => Instr::Ge,
+                BinOp::Le => Instr::Le,
+                BinOp::And | BinOp::Or => unreachable!("logical operators are compiled by compile_logical"),
+            });
+        }
+        ExprKind::Call { name, args } => {
+            // a callee not compiled yet may be defined further down
+            let addr = if e.functions.contains_key(name) {
+                Some(check_call(e, name, args.len(), expr.span)?)
+            } else {
+                None
+            };
+            for arg in args {
+                compile_expr(e, arg)?;
+            }
+            if addr.is_none() {
+                e.pending_calls.push((e.pc(), name.clone(), args.len(), expr.span));
             }
+            e.emit(Instr::Call(addr.unwrap_or(0), args.len()));
+        }
+        ExprKind::Tuple(_) => {
+            return Err(BytecodeError::new("tuples are only supported in a destructuring `let`", expr.span));
+        }
+        ExprKind::Field { name, .. } => {
+            return Err(BytecodeError::new(format!("field access (`.{}`) is not supported yet", name), expr.span));
+        }
+    }
+    Ok(())
+}
+
+/// `&&` and `||` with short-circuit evaluation: the right operand is skipped
+/// once the left one decides the result. Pushes a `Bool`.
+fn compile_logical(e: &mut Emitter, left: &Expr, op: BinOp, right: &Expr) -> Result<(), BytecodeError> {
+    compile_expr(e, left)?;
+    let left_false = e.pc();
+    e.emit(Instr::JumpIfFalse(0)); // placeholder
+    let mut to_true = Vec::new();
+    if op == BinOp::Or {
+        to_true.push(e.pc());
+        e.emit(Instr::Jump(0)); // placeholder
+    }
+    let rhs = e.pc();
+    compile_expr(e, right)?;
+    let right_false = e.pc();
+    e.emit(Instr::JumpIfFalse(0)); // placeholder
+    let push_true = e.pc();
+    e.emit(Instr::PushBool(true));
+    let to_end = e.pc();
+    e.emit(Instr::Jump(0)); // placeholder
+    let push_false = e.pc();
+    e.emit(Instr::PushBool(false));
+    let end = e.pc();
+
+    // `false && _` is false; `false || x` is decided by x
+    e.patch(left_false, Instr::JumpIfFalse(if op == BinOp::And { push_false } else { rhs }));
+    e.patch(right_false, Instr::JumpIfFalse(push_false));
+    for pos in to_true {
+        e.patch(pos, Instr::Jump(push_true));
+    }
+    e.patch(to_end, Instr::Jump(end));
+    Ok(())
+}
+
+/// A runtime value on the VM stack or in a variable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Float(f64),
+    Bool(bool),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "Int",
+            Value::Float(_) => "Float",
+            Value::Bool(_) => "Bool",
+        }
+    }
+
+    /// Truthiness used by conditional jumps: `false` and `0` are false.
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Int(n) => *n != 0,
+            Value::Float(x) => *x != 0.0,
+            Value::Bool(b) => *b,
+        }
+    }
+}
+
+/// Ints order against ints and floats against floats; any comparison with NaN
+/// is false. Values of different types are unordered (`None`), and the VM
+/// reports comparing them as a type error before it gets here.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+/// Error raised while executing bytecode. `instr` names the opcode that failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    StackUnderflow { instr: &'static str, ip: usize },
+    /// `Load` of a variable that was never stored (or whose block has ended).
+    UndefinedVariable { name: String, ip: usize },
+    /// A jump or call whose target lies past the end of the program.
+    BadJump { addr: usize, ip: usize },
+    /// `side` is the operand position that had the wrong type ("left"/"right").
+    TypeMismatch {
+        instr: &'static str,
+        side: &'static str,
+        expected: &'static str,
+        found: &'static str,
+        ip: usize,
+    },
+    /// Integer arithmetic whose result does not fit an i32.
+    Overflow { instr: &'static str, ip: usize },
+    /// `Div` or `Mod` with a zero right operand.
+    DivisionByZero { instr: &'static str, ip: usize },
+    /// A `Call` passing a different number of arguments than the callee's
+    /// `Enter` expects.
+    WrongArgumentCount { expected: usize, found: usize, ip: usize },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow { instr, ip } => write!(f, "stack underflow in {} at ip={}", instr, ip),
+            VmError::UndefinedVariable { name, ip } => write!(f, "undefined variable {} at ip={}", name, ip),
+            VmError::BadJump { addr, ip } => write!(f, "jump target {} is out of range at ip={}", addr, ip),
+            VmError::TypeMismatch { instr, side, expected, found, ip } => write!(
+                f,
+                "{} expected {} on the {}, got {} at ip={}",
+                instr, expected, side, found, ip
+            ),
+            VmError::Overflow { instr, ip } => write!(f, "integer overflow in {} at ip={}", instr, ip),
+            VmError::DivisionByZero { instr, ip } => write!(f, "division by zero in {} at ip={}", instr, ip),
+            VmError::WrongArgumentCount { expected, found, ip } => write!(
+                f,
+                "Call passes {} argument(s) to a function taking {} at ip={}",
+                found, expected, ip
+            ),
         }
     }
 }
 
 pub struct VM {
     pub ip: usize,
-    pub stack: Vec<i32>,
+    pub stack: Vec<Value>,
+    /// return addresses of the active calls, innermost last
+    pub call_stack: Vec<usize>,
+    /// value of a top-level `return`, which ends the program
+    pub exit_value: Option<Value>,
     pub code: Vec<Instr>,
-    pub vars: HashMap<String, i32>,
+    /// top-level variables
+    pub vars: HashMap<String, Value>,
+    /// variables of the active calls, innermost last (one per `call_stack`
+    /// entry), so each activation of a recursive function has its own
+    pub frames: Vec<HashMap<String, Value>>,
 }
 
 impl VM {
     pub fn new(code: Vec<Instr>) -> Self {
-        VM { ip: 0, stack: Vec::new(), code, vars: HashMap::new() }
+        VM {
+            ip: 0,
+            stack: Vec::new(),
+            call_stack: Vec::new(),
+            exit_value: None,
+            code,
+            vars: HashMap::new(),
+            frames: Vec::new(),
+        }
     }
 
-    pub fn run(&mut self) {
+    /// Value of `name`: from the innermost call's frame, or else a top-level variable.
+    fn load(&self, name: &str) -> Option<Value> {
+        self.frames.last().and_then(|frame| frame.get(name)).or_else(|| self.vars.get(name)).copied()
+    }
+
+    /// Store into the innermost call's frame, unless `name` is a top-level
+    /// variable that the frame does not shadow. The emitter gives every
+    /// variable declared inside a function its own slot name, so those never
+    /// collide with top-level ones.
+    fn store(&mut self, name: String, value: Value) {
+        match self.frames.last_mut() {
+            Some(frame) if frame.contains_key(&name) || !self.vars.contains_key(&name) => {
+                frame.insert(name, value);
+            }
+            _ => {
+                self.vars.insert(name, value);
+            }
+        }
+    }
+
+    fn pop(&mut self, instr: &'static str) -> Result<Value, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow { instr, ip: self.ip })
+    }
+
+    /// Apply an arithmetic `instr` to its operands and push the result: a
+    /// checked i32 operation for two Ints, or an f64 one for two Floats, which
+    /// like LLVM's gives inf or NaN rather than an error when dividing by zero.
+    fn arith(
+        &mut self,
+        instr: &'static str,
+        int_op: fn(i32, i32) -> Option<i32>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<(), VmError> {
+        let result = match self.pop_ordered_operands(instr)? {
+            (Value::Int(a), Value::Int(b)) => Value::Int(int_op(a, b).ok_or(match (instr, b) {
+                ("Div" | "Mod", 0) => VmError::DivisionByZero { instr, ip: self.ip },
+                _ => VmError::Overflow { instr, ip: self.ip },
+            })?),
+            (Value::Float(a), Value::Float(b)) => Value::Float(float_op(a, b)),
+            _ => unreachable!("the operands are two Ints or two Floats"),
+        };
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// Pop the operands of arithmetic or an ordering comparison: two ints or
+    /// two floats.
+    fn pop_ordered_operands(&mut self, instr: &'static str) -> Result<(Value, Value), VmError> {
+        let b = self.pop(instr)?;
+        let a = self.pop(instr)?;
+        let (side, expected, found) = match (a, b) {
+            (Value::Int(_), Value::Int(_)) | (Value::Float(_), Value::Float(_)) => return Ok((a, b)),
+            (Value::Bool(_), _) => ("left", "Int or Float", a.type_name()),
+            _ => ("right", a.type_name(), b.type_name()),
+        };
+        Err(VmError::TypeMismatch { instr, side, expected, found, ip: self.ip })
+    }
+
+    /// Pop the operands of an equality test: two values of the same type.
+    fn pop_comparable_operands(&mut self, instr: &'static str) -> Result<(Value, Value), VmError> {
+        let b = self.pop(instr)?;
+        let a = self.pop(instr)?;
+        if std::mem::discriminant(&a) != std::mem::discriminant(&b) {
+            return Err(VmError::TypeMismatch {
+                instr,
+                side: "right",
+                expected: a.type_name(),
+                found: b.type_name(),
+                ip: self.ip,
+            });
+        }
+        Ok((a, b))
+    }
+
+    /// Check a jump or call target. `code.len()` itself is allowed and simply
+    /// ends the program.
+    fn jump_target(&self, addr: usize) -> Result<usize, VmError> {
+        if addr <= self.code.len() {
+            Ok(addr)
+        } else {
+            Err(VmError::BadJump { addr, ip: self.ip })
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), VmError> {
         loop {
             if self.ip >= self.code.len() { break; }
             match &self.code[self.ip] {
-                Instr::PushInt(n) => { self.stack.push(*n); self.ip += 1; }
+                Instr::PushInt(n) => { self.stack.push(Value::Int(*n)); self.ip += 1; }
+                Instr::PushFloat(x) => { self.stack.push(Value::Float(*x)); self.ip += 1; }
+                Instr::PushBool(b) => { self.stack.push(Value::Bool(*b)); self.ip += 1; }
                 Instr::Load(name) => {
-                    let v = *self.vars.get(name).unwrap_or(&0);
+                    let v = self.load(name).ok_or_else(|| VmError::UndefinedVariable {
+                        name: name.clone(),
+                        ip: self.ip,
+                    })?;
                     self.stack.push(v);
                     self.ip += 1;
                 }
                 Instr::Store(name) => {
-                    let v = self.stack.pop().expect("stack underflow on Store");
-                    self.vars.insert(name.clone(), v);
+                    let name = name.clone();
+                    let v = self.pop("Store")?;
+                    self.store(name, v);
                     self.ip += 1;
                 }
                 Instr::Add => {
-                    let b = self.stack.pop().expect("stack underflow Add");
-                    let a = self.stack.pop().expect("stack underflow Add");
-                    self.stack.push(a + b);
+                    self.arith("Add", i32::checked_add, |a, b| a + b)?;
                     self.ip += 1;
                 }
                 Instr::Sub => {
-                    let b = self.stack.pop().expect("stack underflow Sub");
-                    let a = self.stack.pop().expect("stack underflow Sub");
-                    self.stack.push(a - b);
+                    self.arith("Sub", i32::checked_sub, |a, b| a - b)?;
                     self.ip += 1;
                 }
                 Instr::Mul => {
-                    let b = self.stack.pop().expect("stack underflow Mul");
-                    let a = self.stack.pop().expect("stack underflow Mul");
-                    self.stack.push(a * b);
+                    self.arith("Mul", i32::checked_mul, |a, b| a * b)?;
                     self.ip += 1;
                 }
                 Instr::Div => {
-                    let b = self.stack.pop().expect("stack underflow Div");
-                    let a = self.stack.pop().expect("stack underflow Div");
-                    self.stack.push(a / b);
+                    self.arith("Div", i32::checked_div, |a, b| a / b)?;
+                    self.ip += 1;
+                }
+                Instr::Mod => {
+                    self.arith("Mod", i32::checked_rem, |a, b| a % b)?;
                     self.ip += 1;
                 }
                 Instr::Gt => {
-                    let b = self.stack.pop().expect("stack underflow Gt");
-                    let a = self.stack.pop().expect("stack underflow Gt");
-                    self.stack.push((a > b) as i32);
+                    let (a, b) = self.pop_ordered_operands("Gt")?;
+                    self.stack.push(Value::Bool(a > b));
                     self.ip += 1;
                 }
                 Instr::Lt => {
-                    let b = self.stack.pop().expect("stack underflow Lt");
-                    let a = self.stack.pop().expect("stack underflow Lt");
-                    self.stack.push((a < b) as i32);
+                    let (a, b) = self.pop_ordered_operands("Lt")?;
+                    self.stack.push(Value::Bool(a < b));
                     self.ip += 1;
                 }
                 Instr::Eq => {
-                    let b = self.stack.pop().expect("stack underflow Eq");
-                    let a = self.stack.pop().expect("stack underflow Eq");
-                    self.stack.push((a == b) as i32);
+                    let (a, b) = self.pop_comparable_operands("Eq")?;
+                    self.stack.push(Value::Bool(a == b));
                     self.ip += 1;
                 }
                 Instr::Neq => {
-                    let b = self.stack.pop().expect("stack underflow Neq");
-                    let a = self.stack.pop().expect("stack underflow Neq");
-                    self.stack.push((a != b) as i32);
+                    let (a, b) = self.pop_comparable_operands("Neq")?;
+                    self.stack.push(Value::Bool(a != b));
+                    self.ip += 1;
+                }
+                Instr::Ge => {
+                    let (a, b) = self.pop_ordered_operands("Ge")?;
+                    self.stack.push(Value::Bool(a >= b));
+                    self.ip += 1;
+                }
+                Instr::Le => {
+                    let (a, b) = self.pop_ordered_operands("Le")?;
+                    self.stack.push(Value::Bool(a <= b));
+                    self.ip += 1;
+                }
+                Instr::Neg => {
+                    let negated = match self.pop("Neg")? {
+                        Value::Int(a) => Value::Int(a.checked_neg().ok_or(VmError::Overflow { instr: "Neg", ip: self.ip })?),
+                        Value::Float(x) => Value::Float(-x),
+                        other => {
+                            return Err(VmError::TypeMismatch {
+                                instr: "Neg",
+                                side: "operand",
+                                expected: "Int or Float",
+                                found: other.type_name(),
+                                ip: self.ip,
+                            });
+                        }
+                    };
+                    self.stack.push(negated);
+                    self.ip += 1;
+                }
+                Instr::Not => {
+                    let a = self.pop("Not")?;
+                    self.stack.push(Value::Bool(!a.is_truthy()));
+                    self.ip += 1;
+                }
+                Instr::And => {
+                    let b = self.pop("And")?;
+                    let a = self.pop("And")?;
+                    self.stack.push(Value::Bool(a.is_truthy() && b.is_truthy()));
+                    self.ip += 1;
+                }
+                Instr::Or => {
+                    let b = self.pop("Or")?;
+                    let a = self.pop("Or")?;
+                    self.stack.push(Value::Bool(a.is_truthy() || b.is_truthy()));
                     self.ip += 1;
                 }
                 Instr::Jump(addr) => {
-                    self.ip = *addr;
+                    self.ip = self.jump_target(*addr)?;
                 }
                 Instr::JumpIfFalse(addr) => {
-                    let v = self.stack.pop().expect("stack underflow JumpIfFalse");
-                    if v == 0 { self.ip = *addr; } else { self.ip += 1; }
+                    let addr = self.jump_target(*addr)?;
+                    let v = self.pop("JumpIfFalse")?;
+                    if !v.is_truthy() { self.ip = addr; } else { self.ip += 1; }
                 }
+                Instr::Call(addr, argc) => {
+                    let addr = self.jump_target(*addr)?;
+                    if let Some(&Instr::Enter(arity)) = self.code.get(addr)
+                        && arity != *argc
+                    {
+                        return Err(VmError::WrongArgumentCount { expected: arity, found: *argc, ip: self.ip });
+                    }
+                    self.call_stack.push(self.ip + 1);
+                    self.frames.push(HashMap::new());
+                    self.ip = addr;
+                }
+                // the result stays on the stack for the caller
+                Instr::Ret => match self.call_stack.pop() {
+                    Some(ret) => {
+                        self.frames.pop();
+                        self.ip = ret;
+                    }
+                    // `return` at top level ends the program
+                    None => {
+                        self.exit_value = Some(self.pop("Ret")?);
+                        break;
+                    }
+                },
+                // checked by the `Call` that jumped here
+                Instr::Enter(_) => { self.ip += 1; }
                 Instr::Pop => { self.stack.pop(); self.ip += 1; }
                 Instr::Halt => { break; }
             }
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn compile(src: &str) -> Result<Vec<Instr>, BytecodeError> {
+        compile_program(&Parser::new(Lexer::new(src.to_string()).tokenize().unwrap()).parse().unwrap())
+    }
+
+    fn run(code: Vec<Instr>) -> Result<Vec<Value>, VmError> {
+        let mut vm = VM::new(code);
+        vm.run()?;
+        Ok(vm.stack)
+    }
+
+    #[test]
+    fn functions_can_be_called_above_their_definition() {
+        let code = compile("let x = twice(4); fn twice(n) { return n * 2; } let y = twice(x);").unwrap();
+        let mut vm = VM::new(code);
+        vm.run().unwrap();
+        assert_eq!(vm.vars["x"], Value::Int(8));
+        assert_eq!(vm.vars["y"], Value::Int(16));
+        let err = compile("let x = twice(4, 5); fn twice(n) { return n * 2; }").unwrap_err();
+        assert_eq!(err.message, "`twice` takes 1 argument(s) but 2 were supplied");
+    }
+
+    /// Compile and run `src`, returning its top-level variables.
+    fn run_source(src: &str) -> HashMap<String, Value> {
+        let mut vm = VM::new(compile(src).unwrap());
+        vm.run().unwrap();
+        vm.vars
+    }
+
+    #[test]
+    fn break_and_continue_leave_the_innermost_loop() {
+        let vars = run_source(
+            "let i = 0; let odd = 0;
+             while i < 10 { i = i + 1; if i % 2 == 0 { continue; } if i > 7 { break; } odd = odd + i; }",
+        );
+        assert_eq!((vars["i"], vars["odd"]), (Value::Int(9), Value::Int(16)));
+        let vars = run_source(
+            "let outer = 0; let inner = 0;
+             while outer < 3 { outer = outer + 1; let j = 0; while true { j = j + 1; inner = inner + 1; if j == 2 { break; } } }",
+        );
+        assert_eq!((vars["outer"], vars["inner"]), (Value::Int(3), Value::Int(6)));
+    }
+
+    #[test]
+    fn do_while_runs_its_body_before_the_check() {
+        let vars = run_source("let n = 10; do { n = n + 1; } while n < 5;");
+        assert_eq!(vars["n"], Value::Int(11));
+        let vars = run_source("let n = 0; let s = 0; do { n = n + 1; if n == 2 { continue; } s = s + n; } while n < 4;");
+        assert_eq!((vars["n"], vars["s"]), (Value::Int(4), Value::Int(8)));
+        let vars = run_source("let n = 0; do { n = n + 1; if n == 3 { break; } } while true;");
+        assert_eq!(vars["n"], Value::Int(3));
+    }
+
+    #[test]
+    fn disassembly_lists_each_instruction_with_its_index() {
+        let code = compile("let x = 4; if x > 3 { x = 1; } else { x = 2; }").unwrap();
+        let expected = "\
+0000 PushInt 4
+0001 Store x
+0002 Load x
+0003 PushInt 3
+0004 Gt
+0005 JumpIfFalse 9
+0006 PushInt 1
+0007 Store x
+0008 Jump 11
+0009 PushInt 2
+0010 Store x
+0011 Halt
+";
+        assert_eq!(disassemble(&code), expected);
+    }
+
+    #[test]
+    fn disassembly_assembles_back_to_the_same_code() {
+        let src = "fn fact(n) { if n < 2 { return 1; } return n * fact(n - 1); }
+                   let x = fact(5); let f = 2.5; let b = !(x > 3) || f == 2.5;
+                   do { x = x - 1; } while x > 100;";
+        let code = compile(src).unwrap();
+        assert_eq!(assemble(&disassemble(&code)), Ok(code.clone()));
+        let plain: String = code.iter().map(|instr| format!("{}\n", instr)).collect();
+        assert_eq!(assemble(&plain), Ok(code));
+        assert_eq!(assemble("0003 Jump 1 2"), Err("line 1: too many operands in `0003 Jump 1 2`".to_string()));
+    }
+
+    #[test]
+    fn unsupported_code_is_a_compile_error() {
+        let err = compile("while true { fn f() { break; } }").unwrap_err();
+        assert_eq!(err.message, "`break` or `continue` outside of a loop");
+        let err = compile("fn f(a) { return a; } let x = f(1, 2);").unwrap_err();
+        assert_eq!(err.message, "`f` takes 1 argument(s) but 2 were supplied");
+        assert_eq!(err.span, Span::new(1, 31, 1, 38));
+        assert_eq!(compile("let x = g(1);").unwrap_err().message, "call to unknown function `g`");
+        assert!(compile("let x = 5000000000;").unwrap_err().message.contains("only supports i32 literals"));
+        assert!(compile("let x = (1, 2);").is_err());
+    }
+
+    #[test]
+    fn operands_of_the_wrong_type_are_a_type_mismatch() {
+        use Instr::*;
+        let err = run(vec![PushInt(1), PushBool(true), Add]).unwrap_err();
+        assert_eq!(err, VmError::TypeMismatch { instr: "Add", side: "right", expected: "Int", found: "Bool", ip: 2 });
+        assert_eq!(err.to_string(), "Add expected Int on the right, got Bool at ip=2");
+        assert_eq!(
+            run(vec![PushFloat(1.0), PushInt(2), Lt]),
+            Err(VmError::TypeMismatch { instr: "Lt", side: "right", expected: "Float", found: "Int", ip: 2 })
+        );
+        assert_eq!(run(vec![Add]), Err(VmError::StackUnderflow { instr: "Add", ip: 0 }));
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error() {
+        use Instr::*;
+        assert_eq!(run(vec![PushInt(i32::MAX), PushInt(1), Add]), Err(VmError::Overflow { instr: "Add", ip: 2 }));
+        assert_eq!(run(vec![PushInt(i32::MIN), PushInt(1), Sub]), Err(VmError::Overflow { instr: "Sub", ip: 2 }));
+        assert_eq!(run(vec![PushInt(65536), PushInt(65536), Mul]), Err(VmError::Overflow { instr: "Mul", ip: 2 }));
+        assert_eq!(run(vec![PushInt(i32::MIN), PushInt(-1), Div]), Err(VmError::Overflow { instr: "Div", ip: 2 }));
+        assert_eq!(run(vec![PushInt(i32::MIN), Neg]), Err(VmError::Overflow { instr: "Neg", ip: 1 }));
+        assert_eq!(run(vec![PushInt(7), PushInt(0), Mod]), Err(VmError::DivisionByZero { instr: "Mod", ip: 2 }));
+        assert_eq!(run(vec![PushInt(7), PushInt(-2), Div]), Ok(vec![Value::Int(-3)]));
+    }
+
+    #[test]
+    fn equality_compares_values_of_the_same_type() {
+        use Instr::*;
+        assert_eq!(run(vec![PushBool(true), PushBool(false), Eq]), Ok(vec![Value::Bool(false)]));
+        assert_eq!(run(vec![PushBool(true), PushBool(true), Eq]), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run(vec![PushFloat(1.5), PushFloat(1.5), Eq]), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run(vec![PushFloat(1.5), PushFloat(2.5), Neq]), Ok(vec![Value::Bool(true)]));
+        assert_eq!(run(vec![PushInt(3), PushInt(3), Neq]), Ok(vec![Value::Bool(false)]));
+        assert_eq!(
+            run(vec![PushInt(1), PushBool(true), Eq]),
+            Err(VmError::TypeMismatch { instr: "Eq", side: "right", expected: "Int", found: "Bool", ip: 2 })
+        );
+    }
+
+    #[test]
+    fn assembler_skips_comments_and_rejects_bad_lines() {
+        assert_eq!(assemble("  ; setup\n\nPushInt -5 ; operand\nHalt"), Ok(vec![Instr::PushInt(-5), Instr::Halt]));
+        assert_eq!(assemble("Add 3"), Err("line 1: Add takes no operand".to_string()));
+        assert_eq!(assemble("Halt\nPushInt"), Err("line 2: PushInt expects an integer".to_string()));
+        assert_eq!(assemble("Foo"), Err("line 1: unknown instruction `Foo`".to_string()));
+    }
+
+    #[test]
+    fn block_locals_are_not_visible_after_the_block() {
+        let vars = run_source("let x = 1; if 1 { let x = 2; let y = 3; } let z = x;");
+        assert_eq!(vars["z"], Value::Int(1));
+        assert!(!vars.contains_key("y"));
+        let err = run(compile("if 1 { let y = 3; } let z = y;").unwrap()).unwrap_err();
+        assert!(matches!(err, VmError::UndefinedVariable { ref name, .. } if name == "y"), "{}", err);
+    }
+
+    #[test]
+    fn zero_argument_functions_can_be_called() {
+        let vars = run_source("fn now() -> i32 { return 5; } let x = now(); now();");
+        assert_eq!(vars["x"], Value::Int(5));
+    }
+
+    #[test]
+    fn jumps_outside_the_code_are_errors() {
+        let mut vm = VM::new(assemble("PushInt 1\nJump 9\nHalt").unwrap());
+        assert_eq!(vm.run(), Err(VmError::BadJump { addr: 9, ip: 1 }));
+        // jumping to one past the end just finishes the program
+        assert_eq!(run(assemble("PushBool true\nJumpIfFalse 3\nHalt").unwrap()), Ok(vec![]));
+        assert_eq!(run(assemble("Jump 1").unwrap()), Ok(vec![]));
+    }
+
+    #[test]
+    fn top_level_return_is_the_exit_value() {
+        let mut vm = VM::new(compile("let a = 3; return a + 4; let b = 1;").unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.exit_value, Some(Value::Int(7)));
+        assert!(!vm.vars.contains_key("b"));
+    }
+
+    #[test]
+    fn literal_operands_are_folded() {
+        assert_eq!(compile("let a = 2 + 3;").unwrap(), vec![Instr::PushInt(5), Instr::Store("a".to_string()), Instr::Halt]);
+        assert_eq!(compile("let a = 2 * 3 + 4 * 5;").unwrap()[0], Instr::PushInt(26));
+        assert_eq!(compile("let a = 1 < 2;").unwrap()[0], Instr::PushBool(true));
+        // division by zero is left for the VM to report
+        assert_eq!(compile("let a = 1 / 0;").unwrap()[2], Instr::Div);
+    }
+
+    #[test]
+    fn literals_are_pushed_at_their_resolved_type() {
+        let analyzed = |src: &str| {
+            let mut program = Parser::new(Lexer::new(src.to_string()).tokenize().unwrap()).parse().unwrap();
+            crate::semantic::SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+            compile_program(&program)
+        };
+        // f64 literals are not folded as integers
+        let code = analyzed("let h: f64 = 7 / 2; h;").unwrap();
+        assert_eq!(code[..3], [Instr::PushFloat(7.0), Instr::PushFloat(2.0), Instr::Div]);
+        let err = analyzed("let a: i64 = 3 * 1000000000; a;").unwrap_err();
+        assert_eq!(err.message, "the bytecode backend does not support i64 values yet, found 3 used as i64");
+    }
+
+    #[test]
+    fn arithmetic_works_on_floats() {
+        use Instr::*;
+        assert_eq!(run(vec![PushFloat(1.5), PushFloat(2.25), Add]), Ok(vec![Value::Float(3.75)]));
+        assert_eq!(run(vec![PushFloat(1.5), PushFloat(2.0), Sub]), Ok(vec![Value::Float(-0.5)]));
+        assert_eq!(run(vec![PushFloat(1.5), PushFloat(3.0), Mul]), Ok(vec![Value::Float(4.5)]));
+        assert_eq!(run(vec![PushFloat(7.0), PushFloat(2.0), Div]), Ok(vec![Value::Float(3.5)]));
+        assert_eq!(run(vec![PushFloat(7.5), PushFloat(2.0), Mod]), Ok(vec![Value::Float(1.5)]));
+        assert_eq!(run(vec![PushFloat(2.5), Neg]), Ok(vec![Value::Float(-2.5)]));
+        assert_eq!(run(vec![PushFloat(1.0), PushFloat(0.0), Div]), Ok(vec![Value::Float(f64::INFINITY)]));
+        assert_eq!(
+            run(vec![PushFloat(1.0), PushInt(2), Add]),
+            Err(VmError::TypeMismatch { instr: "Add", side: "right", expected: "Float", found: "Int", ip: 2 })
+        );
+        assert_eq!(
+            run(vec![PushBool(true), Neg]),
+            Err(VmError::TypeMismatch { instr: "Neg", side: "operand", expected: "Int or Float", found: "Bool", ip: 1 })
+        );
+
+        let mut program = Parser::new(Lexer::new("let h: f64 = 7 / 2; return -h * 2.0 + 0.5;".to_string()).tokenize().unwrap())
+            .parse()
+            .unwrap();
+        crate::semantic::SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        let mut vm = VM::new(compile_program(&program).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.exit_value, Some(Value::Float(-6.5)));
+    }
+
+    #[test]
+    fn tuple_lets_bind_each_name() {
+        let mut vm = VM::new(compile("let (a, b) = (3, 4); return a + b;").unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.exit_value, Some(Value::Int(7)));
+
+        // the right-hand side is read before either name is bound
+        let mut vm = VM::new(compile("let a = 1; let b = 2; if 1 { let (a, b) = (b, a); return a * 10 + b; }").unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.exit_value, Some(Value::Int(21)));
+    }
+
+    #[test]
+    fn modulo_takes_the_remainder() {
+        let mut vm = VM::new(compile("let x = 7; return x % 3 + 10 % 4 * 2;").unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.exit_value, Some(Value::Int(5)));
+    }
+
+    #[test]
+    fn logical_operators_skip_the_right_side_when_decided() {
+        let src = "let hits = 0;
+                   fn f() -> bool { hits = hits + 1; return true; }
+                   let a = false && f(); let b = true || f(); let c = true && f(); let d = false || f();";
+        let vars = run_source(src);
+        assert_eq!(vars["hits"], Value::Int(2));
+        let results: Vec<_> = ["a", "b", "c", "d"].iter().map(|name| vars[*name]).collect();
+        assert_eq!(results, [false, true, true, true].map(Value::Bool));
+    }
+
+    #[test]
+    fn unary_operators_negate_and_invert() {
+        for (src, expected) in [
+            ("return -5;", Value::Int(-5)),
+            ("return !false;", Value::Bool(true)),
+            ("let a = 2; let b = 3; return -(a + b);", Value::Int(-5)),
+        ] {
+            let mut vm = VM::new(compile(src).unwrap());
+            vm.run().unwrap();
+            assert_eq!(vm.exit_value, Some(expected), "{}", src);
+        }
+    }
+
+    #[test]
+    fn ordering_compares_floats_and_rejects_mixed_types() {
+        use Instr::*;
+        assert_eq!(run(vec![PushFloat(1.5), PushFloat(2.0), Lt]), Ok(vec![Value::Bool(true)]));
+        for op in [Lt, Gt, Le, Ge] {
+            assert_eq!(run(vec![PushFloat(f64::NAN), PushFloat(1.0), op]), Ok(vec![Value::Bool(false)]));
+        }
+        let err = run(vec![PushInt(1), PushBool(true), Lt]).unwrap_err();
+        assert_eq!(err.to_string(), "Lt expected Int on the right, got Bool at ip=2");
+        assert_eq!(assemble("PushFloat NaN\nPushFloat 2.0").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        for (expr, expected) in [("2 + 3 * 4", 14), ("2 * 3 + 4", 10), ("(2 + 3) * 4", 20), ("2 + 3 * 4 - 10 / 2 - 1", 8)] {
+            let mut vm = VM::new(compile(&format!("return {};", expr)).unwrap());
+            vm.run().unwrap();
+            assert_eq!(vm.exit_value, Some(Value::Int(expected)), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn else_if_chains_pick_one_branch() {
+        for (x, expected) in [(1, 10), (5, 20), (9, 30)] {
+            let src = format!("let x = {}; let r = 0; if x < 3 {{ r = 10; }} else if x < 7 {{ r = 20; }} else {{ r = 30; }}", x);
+            assert_eq!(run_source(&src)["r"], Value::Int(expected), "x = {}", x);
+        }
+    }
+
+    #[test]
+    fn for_loops_match_the_equivalent_while() {
+        let for_sum = run_source("let s = 0; for (let i = 0; i < 5; i = i + 1) { s = s + i; }");
+        let while_sum = run_source("let s = 0; let i = 0; while i < 5 { s = s + i; i = i + 1; }");
+        assert_eq!(for_sum["s"], Value::Int(10));
+        assert_eq!(for_sum["s"], while_sum["s"]);
+        // `continue` still runs the step, and every section may be empty
+        let odd_sum = run_source("let s = 0; for (let i = 0; i < 6; i = i + 1) { if i % 2 == 0 { continue; } s = s + i; }");
+        assert_eq!(odd_sum["s"], Value::Int(9));
+        let vars = run_source("let s = 0; let i = 0; for (;;) { if i > 3 { break; } s = s + i; i = i + 1; }");
+        assert_eq!(vars["s"], Value::Int(6));
+    }
+
+    #[test]
+    fn while_loops_run_until_the_condition_fails() {
+        let code = compile("let i = 0; let n = 0; while i < 3 { let step = 1; i = i + step; n = n + 2; }").unwrap();
+        assert!(code.contains(&Instr::Jump(4)), "{:?}", code);
+        let vars = run_source("let i = 0; let n = 0; while i < 3 { let step = 1; i = i + step; n = n + 2; }");
+        assert_eq!(vars["i"], Value::Int(3));
+        assert_eq!(vars["n"], Value::Int(6));
+        // a loop whose condition starts false never runs
+        assert_eq!(run_source("let i = 5; while i < 3 { i = i + 1; }")["i"], Value::Int(5));
+    }
+
+    #[test]
+    fn if_else_runs_exactly_one_branch() {
+        for (x, then_ran, else_ran) in [(1, 1, 0), (5, 0, 1)] {
+            let src = format!("let x = {}; let a = 0; let b = 0; if x < 3 {{ a = 1; }} else {{ b = 1; }}", x);
+            let vars = run_source(&src);
+            assert_eq!((vars["a"], vars["b"]), (Value::Int(then_ran), Value::Int(else_ran)), "x = {}", x);
+        }
+        // without an else, a false condition skips the block
+        assert_eq!(run_source("let a = 0; if false { a = 1; }")["a"], Value::Int(0));
+    }
+
+    #[test]
+    fn demo_program_calls_functions_in_the_vm() {
+        let src = "fn add(a: i32, b: i32) -> i32 { return a + b; } \
+                   fn sub(x: i32, y: i32) -> i32 { let a = x - y; return a; } \
+                   let x: i32 = 5; let y: i32 = 10; let z: i32 = add(x, y); let a = 100; let d = sub(y, x); \
+                   return z * 10 + d + a;";
+        let mut vm = VM::new(compile(src).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.vars["z"], Value::Int(15));
+        // the callees' `a` and parameters leave the caller's variables alone
+        assert_eq!(vm.vars["a"], Value::Int(100));
+        assert_eq!(vm.vars["x"], Value::Int(5));
+        assert_eq!(vm.exit_value, Some(Value::Int(255)));
+    }
+
+    #[test]
+    fn calls_must_pass_the_callees_argument_count() {
+        let code = compile("fn add(a: i32, b: i32) -> i32 { return a + b; } let z = add(2, 3);").unwrap();
+        assert_eq!(code[1], Instr::Enter(2));
+        assert!(code.contains(&Instr::Call(1, 2)), "{:?}", code);
+        assert_eq!(assemble(&disassemble(&code)), Ok(code));
+
+        // a hand-written call to `add` with one argument
+        let code = assemble("Jump 7\nEnter 2\nStore b\nStore a\nLoad a\nLoad b\nAdd\nPushInt 1\nCall 1 1\nHalt").unwrap();
+        assert_eq!(run(code), Err(VmError::WrongArgumentCount { expected: 2, found: 1, ip: 8 }));
+        assert_eq!(assemble("Call 3"), Err("line 1: Call expects an argument count".to_string()));
+    }
+
+    #[test]
+    fn recursive_calls_get_their_own_frames() {
+        let src = "fn fact(n: i32) -> i32 { if n <= 1 { return 1; } let m = n - 1; let r = fact(m); return n * r; } \
+                   fn fib(n: i32) -> i32 { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); } \
+                   let total = 0; fn bump(k: i32) -> i32 { total = total + k; return total; } \
+                   let f = fact(5); let g = fib(10); bump(3); bump(4); return f;";
+        let mut vm = VM::new(compile(src).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.vars["f"], Value::Int(120));
+        assert_eq!(vm.vars["g"], Value::Int(55));
+        // assigning a top-level variable from a function updates the global
+        assert_eq!(vm.vars["total"], Value::Int(7));
+        assert!(vm.frames.is_empty() && vm.call_stack.is_empty());
+        assert_eq!(vm.exit_value, Some(Value::Int(120)));
     }
 }
@@ -1,9 +1,76 @@
+use inkwell::builder::BuilderError;
+use inkwell::basic_block::BasicBlock;
 use inkwell::context::Context;
-use inkwell::values::{BasicValueEnum, IntValue, PointerValue};
+use inkwell::execution_engine::ExecutionEngine;
+use inkwell::types::{BasicType, BasicTypeEnum, IntType};
+use inkwell::values::{BasicValueEnum, FloatValue, FunctionValue, IntValue, PointerValue};
 use inkwell::OptimizationLevel;
-use inkwell::targets::{Target, InitializationConfig, TargetMachine, RelocMode, CodeModel, FileType};
-use crate::ast::{Program, Stmt, Expr};
+use inkwell::targets::{Target, InitializationConfig, RelocMode, CodeModel, FileType, TargetTriple};
+use crate::ast::{BinOp, Literal, Program, Stmt, StmtKind, Expr, ExprKind, Span, Type, UnaryOp};
+use crate::semantic::{const_int, ExprType, PRINT_BUILTIN};
 use std::collections::HashMap;
+use std::fmt;
+
+/// Error produced while lowering the AST to LLVM IR. Carries the span of the
+/// offending node when known so the driver can point at the source.
+#[derive(Debug, Clone)]
+pub struct CodegenError {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl CodegenError {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        CodegenError { message: message.into(), span: Some(span) }
+    }
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}: {}", span, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<BuilderError> for CodegenError {
+    fn from(err: BuilderError) -> Self {
+        CodegenError { message: format!("LLVM builder error: {}", err), span: None }
+    }
+}
+
+/// Target machine settings used when emitting object code.
+#[derive(Debug, Clone)]
+pub struct TargetConfig {
+    pub cpu: String,
+    pub features: String,
+    pub opt_level: OptimizationLevel,
+    pub reloc_mode: RelocMode,
+    pub code_model: CodeModel,
+}
+
+impl Default for TargetConfig {
+    fn default() -> Self {
+        TargetConfig {
+            cpu: "generic".to_string(),
+            features: String::new(),
+            opt_level: OptimizationLevel::Default,
+            reloc_mode: RelocMode::Default,
+            code_model: CodeModel::Default,
+        }
+    }
+}
+
+impl TargetConfig {
+    /// Settings for an object that the system `cc` can link into a runnable
+    /// executable. Code is position independent because most toolchains now
+    /// produce PIE binaries by default, and non-PIC objects fail to link
+    /// there with relocation errors.
+    pub fn native_executable() -> Self {
+        TargetConfig { reloc_mode: RelocMode::PIC, ..TargetConfig::default() }
+    }
+}
 
 pub struct LLVMCodegen<'ctx> {
     pub context: &'ctx Context,
@@ -12,6 +79,19 @@ pub struct LLVMCodegen<'ctx> {
     pub function: Option<inkwell::values::FunctionValue<'ctx>>,
     /// stack of var maps for scoping: each entry maps var name -> alloca pointer
     pub vars_stack: Vec<HashMap<String, PointerValue<'ctx>>>,
+    /// top-level `let`s, which are globals so functions can use them too
+    globals: HashMap<String, PointerValue<'ctx>>,
+    /// `"%d\n"`, created by the first `print`
+    print_format: Option<PointerValue<'ctx>>,
+    /// counter used to give every named temporary a unique, deterministic name
+    tmp_counter: usize,
+    /// enclosing loops, innermost last: (continue target, break target)
+    loops: Vec<(BasicBlock<'ctx>, BasicBlock<'ctx>)>,
+    /// Type of integer literals and untyped `let` bindings: `I32` or `I64`.
+    /// Must match the analyzer's setting.
+    pub default_int_type: ExprType,
+    /// Code generation level of the JIT. Objects use `TargetConfig::opt_level`.
+    pub jit_opt_level: OptimizationLevel,
 }
 
 impl<'ctx> LLVMCodegen<'ctx> {
@@ -27,6 +107,12 @@ impl<'ctx> LLVMCodegen<'ctx> {
             builder,
             function: None,
             vars_stack: vec![],
+            globals: HashMap::new(),
+            print_format: None,
+            tmp_counter: 0,
+            loops: Vec::new(),
+            default_int_type: ExprType::I32,
+            jit_opt_level: OptimizationLevel::None,
         }
     }
 
@@ -38,6 +124,23 @@ impl<'ctx> LLVMCodegen<'ctx> {
         self.vars_stack.last_mut().unwrap()
     }
 
+    /// Helper: next temporary name for `base`, e.g. `addtmp.3`. Numbering is
+    /// per module, so compiling the same program always yields the same IR.
+    fn tmp_name(&mut self, base: &str) -> String {
+        let name = format!("{}.{}", base, self.tmp_counter);
+        self.tmp_counter += 1;
+        name
+    }
+
+    /// Pointer to the variable `name`: the innermost local, or else a global.
+    fn lookup_var(&self, name: &str) -> Option<PointerValue<'ctx>> {
+        self.vars_stack
+            .iter()
+            .rev()
+            .find_map(|map| map.get(name).copied())
+            .or_else(|| self.globals.get(name).copied())
+    }
+
     /// Push / pop scope for local variables
     fn push_scope(&mut self) {
         self.vars_stack.push(HashMap::new());
@@ -47,26 +150,283 @@ impl<'ctx> LLVMCodegen<'ctx> {
         self.vars_stack.pop();
     }
 
+    /// True when the block the builder is positioned in already ends in a
+    /// terminator (e.g. a `return`), so no fallthrough branch or return may be
+    /// appended to it.
+    fn block_terminated(&self) -> bool {
+        self.builder
+            .get_insert_block()
+            .and_then(|bb| bb.get_terminator())
+            .is_some()
+    }
+
+    /// Compile a list of statements. Compilation stops once the current block
+    /// has been terminated, since anything after a `return` is unreachable.
+    fn compile_block(&mut self, stmts: &[Stmt]) -> Result<(), CodegenError> {
+        for s in stmts {
+            if self.block_terminated() {
+                break;
+            }
+            self.compile_stmt(s)?;
+        }
+        Ok(())
+    }
+
     /// Create an alloca in the function entry block and return pointer.
     /// This follows LLVM convention: perform alloca in entry for optimization friendliness.
-    fn create_entry_alloca(&self, name: &str) -> PointerValue<'ctx> {
+    fn create_entry_alloca(&self, name: &str, ty: BasicTypeEnum<'ctx>) -> Result<PointerValue<'ctx>, CodegenError> {
         let function = self.function.expect("function must exist to create entry alloca");
         let entry = function.get_first_basic_block().expect("function entry block expected");
         // Save current insertion point
         let current_bb = self.builder.get_insert_block();
-        // Position at start of entry block
-        self.builder.position_at_end(entry);
-        let i32_type = self.context.i32_type();
-        let alloca = self.builder.build_alloca(i32_type, name);
+        // Position at start of entry block, ahead of any terminator already there
+        match entry.get_first_instruction() {
+            Some(first) => self.builder.position_before(&first),
+            None => self.builder.position_at_end(entry),
+        }
+        let alloca = self.builder.build_alloca(ty, name)?;
         // restore insertion point
         if let Some(bb) = current_bb {
             self.builder.position_at_end(bb);
         }
-        alloca
+        Ok(alloca)
+    }
+
+    /// Integer type of literals and untyped bindings.
+    fn default_int(&self) -> IntType<'ctx> {
+        match self.default_int_type {
+            ExprType::I64 => self.context.i64_type(),
+            _ => self.context.i32_type(),
+        }
+    }
+
+    /// LLVM type of a variable, parameter or result of type `ty`.
+    fn llvm_type_of(&self, ty: Type, span: Span) -> Result<BasicTypeEnum<'ctx>, CodegenError> {
+        match ty {
+            Type::I32 => Ok(self.context.i32_type().into()),
+            Type::I64 => Ok(self.context.i64_type().into()),
+            Type::F64 => Ok(self.context.f64_type().into()),
+            Type::Bool => Ok(self.context.bool_type().into()),
+            Type::Unit => Err(CodegenError::new("type `()` has no values to store", span)),
+        }
+    }
+
+    /// Storage type for a `let` with type `var_type` (as annotated or inferred by
+    /// the analyzer). Untyped bindings use `default_int_type`.
+    fn storage_type(&self, var_type: Option<Type>, span: Span) -> Result<BasicTypeEnum<'ctx>, CodegenError> {
+        match var_type {
+            None => Ok(self.default_int().into()),
+            Some(ty) => self.llvm_type_of(ty, span),
+        }
+    }
+
+    /// Expressions are computed as i1 (booleans), i32, i64 or f64 and
+    /// converted to the variable's type on store. `val` is the value of `expr`.
+    fn store_var(&mut self, ptr: PointerValue<'ctx>, val: BasicValueEnum<'ctx>, expr: &Expr) -> Result<(), CodegenError> {
+        let ty = BasicTypeEnum::try_from(ptr.get_type().get_element_type()).expect("variables have basic types");
+        let val = self.convert_value(val, ty, expr)?;
+        self.builder.build_store(ptr, val)?;
+        Ok(())
+    }
+
+    /// Convert `val`, the value of `expr`, to `ty`. The analyzer rejects
+    /// mixing types, so the only conversions are widening an integer (an i32
+    /// stored in an i64, or a comparison returned as an i32) and an integer
+    /// literal used as a float (`let y: f64 = 2;` when the analyzer did not
+    /// run), which is an `sitofp`. Anything else would silently change the
+    /// value and is an error.
+    fn convert_value(&mut self, val: BasicValueEnum<'ctx>, ty: BasicTypeEnum<'ctx>, expr: &Expr) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        let bool_type = self.context.bool_type();
+        Ok(match (val, ty) {
+            _ if val.get_type() == ty => val,
+            (BasicValueEnum::IntValue(v), BasicTypeEnum::IntType(ty))
+                if ty != bool_type && v.get_type().get_bit_width() < ty.get_bit_width() =>
+            {
+                self.cast_int(v, ty)?.into()
+            }
+            (BasicValueEnum::IntValue(v), BasicTypeEnum::FloatType(ty))
+                if v.get_type() != bool_type && matches!(expr.kind, ExprKind::Number(Literal::Int(_))) =>
+            {
+                let name = self.tmp_name("sitofp");
+                self.builder.build_signed_int_to_float(v, ty, &name)?.into()
+            }
+            _ => {
+                let (from, to) = (self.type_name(val.get_type()), self.type_name(ty));
+                return Err(CodegenError::new(format!("cannot convert {} to {}", from, to), expr.span));
+            }
+        })
+    }
+
+    /// Source name of the LLVM type of a value.
+    fn type_name(&self, ty: BasicTypeEnum<'ctx>) -> String {
+        match ty {
+            BasicTypeEnum::IntType(ty) if ty == self.context.bool_type() => Type::Bool.to_string(),
+            BasicTypeEnum::IntType(ty) => format!("i{}", ty.get_bit_width()),
+            BasicTypeEnum::FloatType(_) => Type::F64.to_string(),
+            other => other.to_string(),
+        }
+    }
+
+    /// Sign-extend or truncate `val` to `ty`. An i1 is zero-extended, so
+    /// `true` becomes 1 rather than -1.
+    fn cast_int(&mut self, val: IntValue<'ctx>, ty: IntType<'ctx>) -> Result<IntValue<'ctx>, CodegenError> {
+        let (from, to) = (val.get_type().get_bit_width(), ty.get_bit_width());
+        Ok(if from == 1 && to > 1 {
+            let name = self.tmp_name("zext");
+            self.builder.build_int_z_extend(val, ty, &name)?
+        } else if from < to {
+            let name = self.tmp_name("sext");
+            self.builder.build_int_s_extend(val, ty, &name)?
+        } else if from > to {
+            let name = self.tmp_name("trunc");
+            self.builder.build_int_truncate(val, ty, &name)?
+        } else {
+            val
+        })
+    }
+
+    /// Bring two operands to the wider of their widths.
+    fn unify_ints(&mut self, l: IntValue<'ctx>, r: IntValue<'ctx>) -> Result<(IntValue<'ctx>, IntValue<'ctx>), CodegenError> {
+        let ty = if l.get_type().get_bit_width() >= r.get_type().get_bit_width() { l.get_type() } else { r.get_type() };
+        Ok((self.cast_int(l, ty)?, self.cast_int(r, ty)?))
+    }
+
+    /// `val != 0` as an i1. A boolean already is one and is used as is.
+    fn build_truthy(&mut self, val: BasicValueEnum<'ctx>, base: &str) -> Result<IntValue<'ctx>, CodegenError> {
+        if val.get_type() == self.context.bool_type().into() {
+            return Ok(val.into_int_value());
+        }
+        let name = self.tmp_name(base);
+        Ok(match val {
+            BasicValueEnum::FloatValue(v) => {
+                let zero = v.get_type().const_zero();
+                self.builder.build_float_compare(inkwell::FloatPredicate::UNE, v, zero, &name)?
+            }
+            _ => {
+                let v = val.into_int_value();
+                self.builder.build_int_compare(inkwell::IntPredicate::NE, v, v.get_type().const_zero(), &name)?
+            }
+        })
+    }
+
+    fn load_var(&mut self, ptr: PointerValue<'ctx>, name: &str) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        Ok(self.builder.build_load(ptr, name)?)
+    }
+
+    /// Return from the current function without an explicit value: 0 of the
+    /// result type, or nothing from a function returning `()`.
+    fn build_default_return(&mut self) -> Result<(), CodegenError> {
+        match self.function.and_then(|f| f.get_type().get_return_type()) {
+            Some(ty) => self.builder.build_return(Some(&ty.const_zero()))?,
+            None => self.builder.build_return(None)?,
+        };
+        Ok(())
+    }
+
+    /// Call `name`, converting each argument to its parameter's type. Returns
+    /// `None` for a function returning `()`.
+    fn compile_call(&mut self, name: &str, args: &[Expr], span: Span) -> Result<Option<BasicValueEnum<'ctx>>, CodegenError> {
+        if name == PRINT_BUILTIN && self.module.get_function(name).is_none() {
+            self.compile_print(args, span)?;
+            return Ok(None);
+        }
+        let Some(func) = self.module.get_function(name) else {
+            return Err(CodegenError::new(format!("unknown function {}", name), span));
+        };
+        // the analyzer normally rejects this, but LLVM would build
+        // invalid IR (or assert) if it slipped through
+        if func.count_params() as usize != args.len() {
+            return Err(CodegenError::new(
+                format!("function {} takes {} argument(s) but {} were supplied", name, func.count_params(), args.len()),
+                span,
+            ));
+        }
+        let mut compiled_args: Vec<inkwell::values::BasicMetadataValueEnum> = Vec::new();
+        for (a, ty) in args.iter().zip(func.get_type().get_param_types()) {
+            let v = self.compile_expr(a)?;
+            let ty = BasicTypeEnum::try_from(ty).expect("parameters have basic types");
+            compiled_args.push(self.convert_value(v, ty, a)?.into());
+        }
+        let tmp = self.tmp_name("calltmp");
+        let call_site = self.builder.build_call(func, &compiled_args, &tmp)?;
+        Ok(call_site.try_as_basic_value().left())
     }
 
-    /// Compile program: add top-level functions and a main wrapper that runs top-level stmts
-    pub fn compile_program(&mut self, program: &Program) {
+    /// The `print(x)` builtin: `printf("%d\n", x)`, with `printf` declared on
+    /// first use. `x` is printed as an i32.
+    fn compile_print(&mut self, args: &[Expr], span: Span) -> Result<(), CodegenError> {
+        let [arg] = args else {
+            return Err(CodegenError::new(format!("{} takes 1 argument but {} were supplied", PRINT_BUILTIN, args.len()), span));
+        };
+        let i32_type = self.context.i32_type();
+        let val = self.compile_expr(arg)?;
+        let val = self.convert_value(val, i32_type.into(), arg)?;
+        let printf = self.module.get_function("printf").unwrap_or_else(|| {
+            let i8_ptr = self.context.i8_type().ptr_type(inkwell::AddressSpace::default());
+            let fn_type = i32_type.fn_type(&[i8_ptr.into()], true);
+            self.module.add_function("printf", fn_type, Some(inkwell::module::Linkage::External))
+        });
+        let format = match self.print_format {
+            Some(format) => format,
+            None => {
+                let format = self.builder.build_global_string_ptr("%d\n", "print_fmt")?.as_pointer_value();
+                self.print_format = Some(format);
+                format
+            }
+        };
+        let name = self.tmp_name("printtmp");
+        self.builder.build_call(printf, &[format.into(), val.into()], &name)?;
+        Ok(())
+    }
+
+    /// Add `name` to the module with its signature but no body yet.
+    fn declare_function(
+        &mut self,
+        name: &str,
+        params: &[(String, Type)],
+        ret_type: Option<Type>,
+        span: Span,
+    ) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let param_types = params
+            .iter()
+            .map(|(_, ty)| Ok(self.llvm_type_of(*ty, span)?.into()))
+            .collect::<Result<Vec<inkwell::types::BasicMetadataTypeEnum>, CodegenError>>()?;
+        let fn_type = match ret_type.unwrap_or(Type::I32) {
+            Type::Unit => self.context.void_type().fn_type(&param_types, false),
+            ty => self.llvm_type_of(ty, span)?.fn_type(&param_types, false),
+        };
+        Ok(self.module.add_function(name, fn_type, None))
+    }
+
+    /// Compile program: add top-level functions and a main wrapper that runs top-level stmts.
+    /// A top-level `return` returns from the wrapper, so its value is the program's exit value.
+    /// Top-level `let`s become globals (see `compile_global`), so functions can use them.
+    /// A program that defines its own `fn main` uses that as the entry point instead, and
+    /// may then only contain function definitions at top level.
+    pub fn compile_program(&mut self, program: &Program) -> Result<(), CodegenError> {
+        // declare every top-level function before compiling any code, so calls
+        // may appear above the callee's definition (and mutual recursion works)
+        for stmt in &program.statements {
+            if let StmtKind::Function { name, params, ret_type, .. } = &stmt.kind {
+                self.declare_function(name, params, *ret_type, stmt.span)?;
+            }
+        }
+        let defines_main = program.statements.iter().any(
+            |s| matches!(&s.kind, StmtKind::Function { name, .. } if name == "main"),
+        );
+        if defines_main {
+            for stmt in &program.statements {
+                if !matches!(stmt.kind, StmtKind::Function { .. }) {
+                    return Err(CodegenError::new(
+                        "top-level statements are not allowed when the program defines `fn main`",
+                        stmt.span,
+                    ));
+                }
+                self.compile_stmt(stmt)?;
+            }
+            return Ok(());
+        }
+
         // Create a main function that will execute top-level statements
         let i32_type = self.context.i32_type();
         let fn_type = i32_type.fn_type(&[], false);
@@ -80,136 +440,243 @@ impl<'ctx> LLVMCodegen<'ctx> {
 
         for stmt in &program.statements {
             // For top-level function definitions, create actual functions rather than code in main
-            match stmt {
-                Stmt::Function { .. } => {
+            match &stmt.kind {
+                StmtKind::Function { .. } => {
                     // generate function definitions separately
-                    self.compile_stmt(stmt);
+                    self.compile_stmt(stmt)?;
+                }
+                StmtKind::VarDecl { name, var_type, value } if !self.block_terminated() => {
+                    let ty = self.storage_type(*var_type, stmt.span)?;
+                    let val = self.compile_expr(value)?;
+                    self.compile_global(name, ty, val, value)?;
+                }
+                StmtKind::VarDeclTuple { names, value } if !self.block_terminated() => {
+                    for (name, val) in names.iter().zip(self.compile_tuple_elements(value)?) {
+                        self.compile_global(name, val.get_type(), val, value)?;
+                    }
                 }
                 _ => {
-                    self.compile_stmt(stmt);
+                    // top-level code after a `return` never runs
+                    if !self.block_terminated() {
+                        self.compile_stmt(stmt)?;
+                    }
                 }
             }
         }
 
         // return 0 at end of main
-        self.builder.build_return(Some(&i32_type.const_int(0, false)));
+        if !self.block_terminated() {
+            self.builder.build_return(Some(&i32_type.const_int(0, false)))?;
+        }
 
         // pop main scope
         self.pop_scope();
+        Ok(())
     }
 
-    fn compile_stmt(&mut self, stmt: &Stmt) {
-        match stmt {
-            Stmt::VarDecl { name, value, .. } => {
-                let val = self.compile_expr(value);
+    /// A top-level `let` becomes an internal global of type `ty`, holding
+    /// `val`. The initializer is compiled in `main` where the `let` appears;
+    /// when the builder folds it to a constant (`let x = 2 * 3;`) that is the
+    /// global's initial value, otherwise the global starts at zero and `main`
+    /// stores the value. Each name of a destructuring `let` is its own global.
+    fn compile_global(
+        &mut self,
+        name: &str,
+        ty: BasicTypeEnum<'ctx>,
+        val: BasicValueEnum<'ctx>,
+        value: &Expr,
+    ) -> Result<(), CodegenError> {
+        let val = self.convert_value(val, ty, value)?;
+        let global = self.module.add_global(ty, None, name);
+        global.set_linkage(inkwell::module::Linkage::Internal);
+        let is_const = match val {
+            BasicValueEnum::IntValue(v) => v.is_const(),
+            BasicValueEnum::FloatValue(v) => v.is_const(),
+            _ => false,
+        };
+        if is_const {
+            global.set_initializer(&val);
+        } else {
+            global.set_initializer(&ty.const_zero());
+            self.builder.build_store(global.as_pointer_value(), val)?;
+        }
+        self.globals.insert(name.to_string(), global.as_pointer_value());
+        Ok(())
+    }
+
+    /// Values of the elements of a destructuring `let`'s initialiser, all
+    /// evaluated before any name is bound.
+    fn compile_tuple_elements(&mut self, value: &Expr) -> Result<Vec<BasicValueEnum<'ctx>>, CodegenError> {
+        let ExprKind::Tuple(elems) = &value.kind else {
+            return Err(CodegenError::new("destructuring `let` needs a tuple initialiser", value.span));
+        };
+        elems.iter().map(|elem| self.compile_expr(elem)).collect()
+    }
+
+    fn compile_stmt(&mut self, stmt: &Stmt) -> Result<(), CodegenError> {
+        match &stmt.kind {
+            StmtKind::VarDecl { name, var_type, value } => {
+                let ty = self.storage_type(*var_type, stmt.span)?;
+                let val = self.compile_expr(value)?;
                 // allocate in entry
-                let ptr = self.create_entry_alloca(name.as_str());
-                self.builder.build_store(ptr, val);
+                let ptr = self.create_entry_alloca(name.as_str(), ty)?;
+                self.store_var(ptr, val, value)?;
                 self.current_vars().insert(name.clone(), ptr);
             }
 
-            Stmt::Assignment { name, value } => {
-                let val = self.compile_expr(value);
-                // find ptr in vars_stack (from innermost outward)
-                for map in self.vars_stack.iter().rev() {
-                    if let Some(ptr) = map.get(name) {
-                        self.builder.build_store(*ptr, val);
-                        return;
-                    }
+            StmtKind::VarDeclTuple { names, value } => {
+                // one alloca per name
+                for (name, val) in names.iter().zip(self.compile_tuple_elements(value)?) {
+                    // each slot takes its element's type, which is the type
+                    // the analyzer gave the name
+                    let ptr = self.create_entry_alloca(name.as_str(), val.get_type())?;
+                    self.store_var(ptr, val, value)?;
+                    self.current_vars().insert(name.clone(), ptr);
                 }
-                panic!("unknown variable {}", name);
             }
 
-            Stmt::IfStmt { condition, then_branch, else_branch } => {
-                let cond_val = self.compile_expr(condition);
+            StmtKind::Assignment { name, value } => {
+                let val = self.compile_expr(value)?;
+                // innermost local first, then the globals
+                let ptr = self.lookup_var(name);
+                if let Some(ptr) = ptr {
+                    return self.store_var(ptr, val, value);
+                }
+                return Err(CodegenError::new(format!("unknown variable {}", name), stmt.span));
+            }
+
+            StmtKind::IfStmt { condition, then_branch, else_branch } => {
+                // A constant condition picks its branch now; the other one is
+                // never emitted.
+                if let Some(c) = const_int(condition) {
+                    let taken = if c != 0 { Some(then_branch) } else { else_branch.as_ref() };
+                    if let Some(stmts) = taken {
+                        self.push_scope();
+                        self.compile_block(stmts)?;
+                        self.pop_scope();
+                    }
+                    return Ok(());
+                }
+
+                let cond_val = self.compile_expr(condition)?;
                 let parent = self.function.expect("function exists");
                 let then_bb = self.context.append_basic_block(parent, "then");
-                let else_bb = self.context.append_basic_block(parent, "else");
+                let else_bb = else_branch
+                    .as_ref()
+                    .map(|_| self.context.append_basic_block(parent, "else"));
                 let after_bb = self.context.append_basic_block(parent, "after_if");
 
-                let cond_bool = self.builder.build_int_compare(
-                    inkwell::IntPredicate::NE,
-                    cond_val.into_int_value(),
-                    self.context.i32_type().const_int(0, false),
-                    "ifcond",
-                );
-
-                // If there is no else branch, branch to after directly from else_bb
-                let has_else = else_branch.is_some();
-                if has_else {
-                    self.builder.build_conditional_branch(cond_bool, then_bb, else_bb);
-                } else {
-                    // use after_bb as else target
-                    self.builder.build_conditional_branch(cond_bool, then_bb, after_bb);
-                }
+                let cond_bool = self.build_truthy(cond_val, "ifcond")?;
+
+                // If there is no else branch, use after_bb as the else target
+                self.builder
+                    .build_conditional_branch(cond_bool, then_bb, else_bb.unwrap_or(after_bb))?;
 
                 // THEN branch
                 self.builder.position_at_end(then_bb);
                 self.push_scope();
-                for s in then_branch {
-                    self.compile_stmt(s);
-                }
+                self.compile_block(then_branch)?;
                 self.pop_scope();
-                self.builder.build_unconditional_branch(after_bb);
+                if !self.block_terminated() {
+                    self.builder.build_unconditional_branch(after_bb)?;
+                }
 
                 // ELSE branch (if any)
-                if let Some(else_stmts) = else_branch {
+                if let (Some(else_bb), Some(else_stmts)) = (else_bb, else_branch) {
                     self.builder.position_at_end(else_bb);
                     self.push_scope();
-                    for s in else_stmts {
-                        self.compile_stmt(s);
-                    }
+                    self.compile_block(else_stmts)?;
                     self.pop_scope();
-                    self.builder.build_unconditional_branch(after_bb);
+                    if !self.block_terminated() {
+                        self.builder.build_unconditional_branch(after_bb)?;
+                    }
                 }
 
-                // continue after
-                self.builder.position_at_end(after_bb);
+                if after_bb.get_first_use().is_none() {
+                    // both branches return: nothing reaches `after_if`, and the
+                    // builder stays in a terminated block, so compile_block
+                    // skips the (unreachable) statements after the `if`
+                    // SAFETY: the block is empty and nothing refers to it
+                    unsafe { after_bb.delete() }.expect("after_if belongs to a function");
+                } else {
+                    // continue after
+                    self.builder.position_at_end(after_bb);
+                }
             }
 
-            Stmt::While { condition, body } => {
+            StmtKind::While { condition, body } => {
+                // `while 0 { .. }` never runs its body
+                if const_int(condition) == Some(0) {
+                    return Ok(());
+                }
+
                 let parent = self.function.expect("function exists");
                 let cond_bb = self.context.append_basic_block(parent, "while_cond");
                 let body_bb = self.context.append_basic_block(parent, "while_body");
                 let after_bb = self.context.append_basic_block(parent, "while_after");
 
                 // jump to condition first
-                self.builder.build_unconditional_branch(cond_bb);
+                self.builder.build_unconditional_branch(cond_bb)?;
 
                 // condition block
                 self.builder.position_at_end(cond_bb);
-                let cond_val = self.compile_expr(condition);
-                let cond_bool = self.builder.build_int_compare(
-                    inkwell::IntPredicate::NE,
-                    cond_val.into_int_value(),
-                    self.context.i32_type().const_int(0, false),
-                    "whilecond",
-                );
-                self.builder.build_conditional_branch(cond_bool, body_bb, after_bb);
+                let cond_val = self.compile_expr(condition)?;
+                let cond_bool = self.build_truthy(cond_val, "whilecond")?;
+                self.builder.build_conditional_branch(cond_bool, body_bb, after_bb)?;
 
                 // body block
                 self.builder.position_at_end(body_bb);
                 self.push_scope();
-                for s in body {
-                    self.compile_stmt(s);
-                }
+                self.loops.push((cond_bb, after_bb));
+                self.compile_block(body)?;
+                self.loops.pop();
                 self.pop_scope();
-                // after body, jump back to cond
-                self.builder.build_unconditional_branch(cond_bb);
+                // after body, jump back to cond (unless the body returned)
+                if !self.block_terminated() {
+                    self.builder.build_unconditional_branch(cond_bb)?;
+                }
 
                 // continue at after_bb
                 self.builder.position_at_end(after_bb);
             }
 
-            Stmt::Function { name, params, ret_type: _, body } => {
-                // Build function type: all params and return type are i32 for now
-                let i32_type = self.context.i32_type();
-                let param_types: Vec<inkwell::types::BasicTypeEnum> =
-                    params.iter().map(|_| i32_type.into()).collect();
-                let fn_type = i32_type.fn_type(&param_types.iter().map(|t| t.as_ref()).collect::<Vec<_>>(), false);
-                let function = self.module.add_function(name.as_str(), fn_type, None);
+            StmtKind::DoWhile { body, condition } => {
+                let parent = self.function.expect("function exists");
+                let body_bb = self.context.append_basic_block(parent, "do_body");
+                let cond_bb = self.context.append_basic_block(parent, "do_cond");
+                let after_bb = self.context.append_basic_block(parent, "do_after");
+
+                // the body runs before the first check
+                self.builder.build_unconditional_branch(body_bb)?;
+                self.builder.position_at_end(body_bb);
+                self.push_scope();
+                self.loops.push((cond_bb, after_bb));
+                self.compile_block(body)?;
+                self.loops.pop();
+                self.pop_scope();
+                if !self.block_terminated() {
+                    self.builder.build_unconditional_branch(cond_bb)?;
+                }
+
+                // condition block, with the back-edge to the body
+                self.builder.position_at_end(cond_bb);
+                let cond_val = self.compile_expr(condition)?;
+                let cond_bool = self.build_truthy(cond_val, "docond")?;
+                self.builder.build_conditional_branch(cond_bool, body_bb, after_bb)?;
+
+                self.builder.position_at_end(after_bb);
+            }
+
+            StmtKind::Function { name, params, ret_type, body } => {
+                let function = match self.module.get_function(name) {
+                    // declared up front by compile_program, not yet defined
+                    Some(function) if function.count_basic_blocks() == 0 => function,
+                    _ => self.declare_function(name, params, *ret_type, stmt.span)?,
+                };
                 let entry = self.context.append_basic_block(function, "entry");
                 let previous_fn = self.function;
+                let previous_bb = self.builder.get_insert_block();
+                let outer_loops = std::mem::take(&mut self.loops);
                 self.function = Some(function);
                 self.builder.position_at_end(entry);
 
@@ -218,127 +685,1077 @@ impl<'ctx> LLVMCodegen<'ctx> {
 
                 // create allocas for parameters and store incoming values
                 for (i, (pname, _ptype)) in params.iter().enumerate() {
-                    let param_val = function.get_nth_param(i as u32).unwrap().into_int_value();
-                    let alloca = self.create_entry_alloca(pname.as_str());
-                    self.builder.build_store(alloca, param_val);
+                    let param_val = function.get_nth_param(i as u32).unwrap();
+                    let alloca = self.create_entry_alloca(pname.as_str(), param_val.get_type())?;
+                    self.builder.build_store(alloca, param_val)?;
                     self.current_vars().insert(pname.clone(), alloca);
                 }
 
                 // compile body
-                for s in body {
-                    self.compile_stmt(s);
-                }
+                self.compile_block(body)?;
 
                 // if no explicit return, default return 0
-                let i32_type = self.context.i32_type();
-                self.builder.build_return(Some(&i32_type.const_int(0, false)));
+                if !self.block_terminated() {
+                    self.build_default_return()?;
+                }
 
-                // pop fn scope and restore previous function
+                // pop fn scope and restore previous function and insertion point
                 self.pop_scope();
                 self.function = previous_fn;
+                self.loops = outer_loops;
+                if let Some(bb) = previous_bb {
+                    self.builder.position_at_end(bb);
+                }
             }
 
-            Stmt::Return(expr_opt) => {
-                if let Some(expr) = expr_opt {
-                    let val = self.compile_expr(expr);
-                    self.builder.build_return(Some(&val.into_int_value()));
-                } else {
-                    let i32_type = self.context.i32_type();
-                    self.builder.build_return(Some(&i32_type.const_int(0, false)));
+            StmtKind::Return(expr_opt) => {
+                let ret_type = self.function.and_then(|f| f.get_type().get_return_type());
+                match (expr_opt, ret_type) {
+                    (Some(expr), Some(ty)) => {
+                        let val = self.compile_expr(expr)?;
+                        let val = self.convert_value(val, ty, expr)?;
+                        self.builder.build_return(Some(&val))?;
+                    }
+                    (Some(_), None) => {
+                        return Err(CodegenError::new("cannot return a value from a function returning ()", stmt.span));
+                    }
+                    (None, _) => self.build_default_return()?,
                 }
             }
 
-            Stmt::ExprStmt(e) => {
-                // evaluate expr and drop result
-                let _ = self.compile_expr(e);
+            StmtKind::Break | StmtKind::Continue => {
+                let Some(&(continue_bb, break_bb)) = self.loops.last() else {
+                    return Err(CodegenError::new("`break` or `continue` outside of a loop", stmt.span));
+                };
+                let target = if matches!(stmt.kind, StmtKind::Break) { break_bb } else { continue_bb };
+                self.builder.build_unconditional_branch(target)?;
+            }
+
+            StmtKind::ExprStmt(e) => {
+                // evaluate expr and drop result; a call may also return nothing
+                if let ExprKind::Call { name, args } = &e.kind {
+                    self.compile_call(name, args, e.span)?;
+                } else {
+                    let _ = self.compile_expr(e)?;
+                }
             }
         }
+        Ok(())
     }
 
-    fn compile_expr(&mut self, expr: &Expr) -> BasicValueEnum<'ctx> {
-        match expr {
-            Expr::Number(n) => self.context.i32_type().const_int(*n as u64, true).into(),
-
-            Expr::Identifier(name) => {
-                // lookup pointer from vars stack
-                for map in self.vars_stack.iter().rev() {
-                    if let Some(ptr) = map.get(name) {
-                        return self.builder.build_load(*ptr, name.as_str());
+    fn compile_expr(&mut self, expr: &Expr) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        let value = match &expr.kind {
+            // an integer literal used as a float, e.g. `let h: f64 = 7 / 2;`
+            ExprKind::Number(Literal::Int(n)) if expr.ty == Some(Type::F64) => {
+                self.context.f64_type().const_float(*n as f64).into()
+            }
+            ExprKind::Number(Literal::Int(n)) => {
+                // use the type the analyzer resolved the literal to; without
+                // one, literals too wide for the default type are i64
+                let fits = |ty: IntType| {
+                    let bits = ty.get_bit_width();
+                    *n >= -(1i128 << (bits - 1)) && *n < 1i128 << (bits - 1)
+                };
+                let candidates = match expr.ty {
+                    Some(Type::I32) => vec![self.context.i32_type()],
+                    Some(Type::I64) => vec![self.context.i64_type()],
+                    _ => vec![self.default_int(), self.context.i64_type()],
+                };
+                match candidates.into_iter().find(|ty| fits(*ty)) {
+                    Some(ty) => ty.const_int(*n as u64, true).into(),
+                    None => {
+                        let ty = expr.ty.unwrap_or(Type::I64);
+                        return Err(CodegenError::new(format!("integer literal {} does not fit in {}", n, ty), expr.span));
                     }
                 }
-                panic!("unknown variable {}", name);
-            }
-
-            Expr::Binary { left, operator, right } => {
-                let l = self.compile_expr(left).into_int_value();
-                let r = self.compile_expr(right).into_int_value();
-                match operator.as_str() {
-                    "+" => self.builder.build_int_add(l, r, "addtmp").into(),
-                    "-" => self.builder.build_int_sub(l, r, "subtmp").into(),
-                    "*" => self.builder.build_int_mul(l, r, "multmp").into(),
-                    "/" => self.builder.build_int_signed_div(l, r, "divtmp").into(),
-                    ">" => self.build_compare(l, r, inkwell::IntPredicate::SGT),
-                    "<" => self.build_compare(l, r, inkwell::IntPredicate::SLT),
-                    "==" => self.build_compare(l, r, inkwell::IntPredicate::EQ),
-                    "!=" => self.build_compare(l, r, inkwell::IntPredicate::NE),
-                    _ => panic!("unknown op {}", operator),
+            }
+            ExprKind::Number(Literal::Bool(b)) => self.context.bool_type().const_int(*b as u64, false).into(),
+            ExprKind::Number(Literal::Float(x)) => self.context.f64_type().const_float(*x).into(),
+
+            ExprKind::Identifier(name) => {
+                // innermost local first, then the globals
+                let ptr = self.lookup_var(name);
+                if let Some(ptr) = ptr {
+                    return self.load_var(ptr, name);
                 }
+                return Err(CodegenError::new(format!("unknown variable {}", name), expr.span));
             }
 
-            Expr::Call { name, args } => {
-                // compile args first
-                let mut compiled_args: Vec<inkwell::values::BasicMetadataValueEnum> = Vec::new();
-                for a in args {
-                    let v = self.compile_expr(a).into_int_value();
-                    compiled_args.push(v.into());
+            ExprKind::Unary { op: UnaryOp::Neg, operand } => match self.compile_expr(operand)? {
+                BasicValueEnum::FloatValue(v) => {
+                    let name = self.tmp_name("negtmp");
+                    self.builder.build_float_neg(v, &name)?.into()
                 }
-                // find function
-                if let Some(func) = self.module.get_function(name.as_str()) {
-                    let call_site = self.builder.build_call(func, &compiled_args, "calltmp");
-                    // returns i32
-                    match call_site.try_as_basic_value().left() {
-                        Some(bv) => bv,
-                        None => panic!("expected function to return a basic value"),
-                    }
-                } else {
-                    panic!("unknown function {}", name);
+                v => {
+                    let name = self.tmp_name("negtmp");
+                    self.builder.build_int_neg(v.into_int_value(), &name)?.into()
                 }
+            },
+
+            ExprKind::Unary { op: UnaryOp::Not, operand } => {
+                let v = self.compile_expr(operand)?;
+                let v = self.build_truthy(v, "nottmp")?;
+                let name = self.tmp_name("nottmp");
+                self.builder.build_not(v, &name)?.into()
             }
-        }
+
+            ExprKind::Binary { left, operator: op @ (BinOp::And | BinOp::Or), right } => {
+                return self.compile_logical(left, *op, right);
+            }
+
+            ExprKind::Binary { left, operator, right } => {
+                let l = self.compile_expr(left)?;
+                let r = self.compile_expr(right)?;
+                if l.is_float_value() || r.is_float_value() {
+                    let f64_type = self.context.f64_type().into();
+                    let l = self.convert_value(l, f64_type, left)?.into_float_value();
+                    let r = self.convert_value(r, f64_type, right)?.into_float_value();
+                    return self.compile_float_binary(l, *operator, r);
+                }
+                let (l, r) = self.unify_ints(l.into_int_value(), r.into_int_value())?;
+                // every integer type is signed so far; an unsigned one (`u32`)
+                // would pass `false` here, based on the operand type
+                self.compile_int_binary(l, *operator, r, true)?
+            }
+
+            ExprKind::Tuple(_) => {
+                return Err(CodegenError::new("tuples are only supported in a destructuring `let`", expr.span));
+            }
+
+            ExprKind::Field { name, .. } => {
+                return Err(CodegenError::new(format!("field access (`.{}`) is not supported yet", name), expr.span));
+            }
+
+            ExprKind::Call { name, args } => match self.compile_call(name, args, expr.span)? {
+                Some(val) => val,
+                None => return Err(CodegenError::new(format!("function {} returns no value", name), expr.span)),
+            },
+        };
+        Ok(value)
     }
 
-    fn build_compare(&self, l: IntValue<'ctx>, r: IntValue<'ctx>, pred: inkwell::IntPredicate) -> BasicValueEnum<'ctx> {
-        let cmp = self.builder.build_int_compare(pred, l, r, "cmptmp");
-        self.builder.build_int_z_extend(cmp, self.context.i32_type(), "bool_to_i32").into()
+    /// `&&` and `||` with short-circuit evaluation: the right operand is only
+    /// computed, in its own block, when the left one does not decide the
+    /// result. A phi picks the i1 result.
+    fn compile_logical(&mut self, left: &Expr, op: BinOp, right: &Expr) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        let parent = self.function.expect("function exists");
+        let l = self.compile_expr(left)?;
+        let l_bool = self.build_truthy(l, "lhs_bool")?;
+        let lhs_bb = self.builder.get_insert_block().expect("builder is positioned");
+        let rhs_bb = self.context.append_basic_block(parent, "logic_rhs");
+        let merge_bb = self.context.append_basic_block(parent, "logic_merge");
+        // `false && _` and `true || _` skip the right operand
+        let short_circuit = match op {
+            BinOp::And => {
+                self.builder.build_conditional_branch(l_bool, rhs_bb, merge_bb)?;
+                self.context.bool_type().const_zero()
+            }
+            _ => {
+                self.builder.build_conditional_branch(l_bool, merge_bb, rhs_bb)?;
+                self.context.bool_type().const_all_ones()
+            }
+        };
+
+        self.builder.position_at_end(rhs_bb);
+        let r = self.compile_expr(right)?;
+        let r_bool = self.build_truthy(r, "rhs_bool")?;
+        let rhs_end = self.builder.get_insert_block().expect("builder is positioned");
+        self.builder.build_unconditional_branch(merge_bb)?;
+
+        self.builder.position_at_end(merge_bb);
+        let phi_name = self.tmp_name("logictmp");
+        let phi = self.builder.build_phi(self.context.bool_type(), &phi_name)?;
+        phi.add_incoming(&[(&short_circuit, lhs_bb), (&r_bool, rhs_end)]);
+        Ok(phi.as_basic_value())
+    }
+
+    fn build_compare(&mut self, l: IntValue<'ctx>, r: IntValue<'ctx>, pred: inkwell::IntPredicate) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        let cmp_name = self.tmp_name("cmptmp");
+        Ok(self.builder.build_int_compare(pred, l, r, &cmp_name)?.into())
+    }
+
+    /// Arithmetic and comparisons on two integer operands of the same width.
+    /// `signed` picks `sdiv`/`srem` and the signed orderings (`SGT`, ...)
+    /// over `udiv`/`urem` and the unsigned ones. Comparisons give an i1.
+    fn compile_int_binary(&mut self, l: IntValue<'ctx>, op: BinOp, r: IntValue<'ctx>, signed: bool) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        use inkwell::IntPredicate;
+        let pred = match op {
+            BinOp::Add => {
+                let name = self.tmp_name("addtmp");
+                return Ok(self.builder.build_int_add(l, r, &name)?.into());
+            }
+            BinOp::Sub => {
+                let name = self.tmp_name("subtmp");
+                return Ok(self.builder.build_int_sub(l, r, &name)?.into());
+            }
+            BinOp::Mul => {
+                let name = self.tmp_name("multmp");
+                return Ok(self.builder.build_int_mul(l, r, &name)?.into());
+            }
+            BinOp::Div => {
+                let name = self.tmp_name("divtmp");
+                let div = if signed {
+                    self.builder.build_int_signed_div(l, r, &name)?
+                } else {
+                    self.builder.build_int_unsigned_div(l, r, &name)?
+                };
+                return Ok(div.into());
+            }
+            BinOp::Mod => {
+                let name = self.tmp_name("modtmp");
+                let rem = if signed {
+                    self.builder.build_int_signed_rem(l, r, &name)?
+                } else {
+                    self.builder.build_int_unsigned_rem(l, r, &name)?
+                };
+                return Ok(rem.into());
+            }
+            BinOp::Gt if signed => IntPredicate::SGT,
+            BinOp::Gt => IntPredicate::UGT,
+            BinOp::Lt if signed => IntPredicate::SLT,
+            BinOp::Lt => IntPredicate::ULT,
+            BinOp::Ge if signed => IntPredicate::SGE,
+            BinOp::Ge => IntPredicate::UGE,
+            BinOp::Le if signed => IntPredicate::SLE,
+            BinOp::Le => IntPredicate::ULE,
+            BinOp::Eq => IntPredicate::EQ,
+            BinOp::Neq => IntPredicate::NE,
+            BinOp::And | BinOp::Or => unreachable!("logical operators are compiled by compile_logical"),
+        };
+        self.build_compare(l, r, pred)
+    }
+
+    /// Arithmetic and comparisons on two f64 operands. Comparisons are
+    /// ordered (false if either side is NaN) and give an i1, as for integers.
+    fn compile_float_binary(&mut self, l: FloatValue<'ctx>, op: BinOp, r: FloatValue<'ctx>) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        use inkwell::FloatPredicate;
+        let pred = match op {
+            BinOp::Add => {
+                let name = self.tmp_name("addtmp");
+                return Ok(self.builder.build_float_add(l, r, &name)?.into());
+            }
+            BinOp::Sub => {
+                let name = self.tmp_name("subtmp");
+                return Ok(self.builder.build_float_sub(l, r, &name)?.into());
+            }
+            BinOp::Mul => {
+                let name = self.tmp_name("multmp");
+                return Ok(self.builder.build_float_mul(l, r, &name)?.into());
+            }
+            BinOp::Div => {
+                let name = self.tmp_name("divtmp");
+                return Ok(self.builder.build_float_div(l, r, &name)?.into());
+            }
+            BinOp::Mod => {
+                let name = self.tmp_name("modtmp");
+                return Ok(self.builder.build_float_rem(l, r, &name)?.into());
+            }
+            BinOp::Gt => FloatPredicate::OGT,
+            BinOp::Lt => FloatPredicate::OLT,
+            BinOp::Eq => FloatPredicate::OEQ,
+            // `!=` is the negation of `==`, so it holds for NaN
+            BinOp::Neq => FloatPredicate::UNE,
+            BinOp::Ge => FloatPredicate::OGE,
+            BinOp::Le => FloatPredicate::OLE,
+            BinOp::And | BinOp::Or => unreachable!("logical operators are compiled by compile_logical"),
+        };
+        let cmp_name = self.tmp_name("cmptmp");
+        Ok(self.builder.build_float_compare(pred, l, r, &cmp_name)?.into())
     }
 
     pub fn dump_module(&self) {
         self.module.print_to_stderr();
     }
 
-    pub fn jit_run(&self) {
-        let execution_engine = self.module.create_jit_execution_engine(OptimizationLevel::None).unwrap();
-        unsafe {
+    /// Check that the module is well-formed IR. Invalid IR (say, a block with
+    /// two terminators) is reported here rather than crashing inside LLVM once
+    /// it is optimised, emitted or executed.
+    pub fn verify(&self) -> Result<(), String> {
+        self.module
+            .verify()
+            .map_err(|e| format!("module failed verification: {}", e.to_string().trim_end()))
+    }
+
+    /// Verify the module and create a JIT engine for it.
+    fn verified_execution_engine(&self) -> Result<ExecutionEngine<'ctx>, String> {
+        self.verify()?;
+        self.module
+            .create_jit_execution_engine(self.jit_opt_level)
+            .map_err(|e| e.to_string())
+    }
+
+    /// JIT-compile and run `main`, returning its result (the program's exit
+    /// value). Verification and engine errors are returned, not panicked on.
+    pub fn jit_run(&self) -> Result<i32, String> {
+        self.jit_run_result(None)
+    }
+
+    /// JIT-run the zero-argument `main` and return its result. With a `timeout`,
+    /// `main` runs on a separate thread and `Err("execution timed out")` is
+    /// returned if it has not finished in time.
+    ///
+    /// Native code cannot be interrupted safely, so a timed-out thread is left
+    /// running (detached) until the process exits, and the execution engine
+    /// holding its code is leaked so that code stays mapped. Use this as a
+    /// watchdog for tests and tools, not for running many untrusted programs in
+    /// one long-lived process.
+    pub fn jit_run_result(&self, timeout: Option<std::time::Duration>) -> Result<i32, String> {
+        let main = self.module.get_function("main").ok_or("module has no main function")?;
+        if main.count_params() != 0 {
+            return Err(format!("main takes {} argument(s); expected none", main.count_params()));
+        }
+
+        let execution_engine = self.verified_execution_engine()?;
+        let main_fn: unsafe extern "C" fn() -> i32 = unsafe {
             let main: inkwell::execution_engine::JitFunction<unsafe extern "C" fn() -> i32> =
-                execution_engine.get_function("main").expect("main func");
-            let res = main.call();
-            println!("JIT main returned {}", res);
+                execution_engine.get_function("main").map_err(|e| e.to_string())?;
+            main.as_raw()
+        };
+
+        let Some(timeout) = timeout else {
+            return Ok(unsafe { main_fn() });
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(unsafe { main_fn() });
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(result) => Ok(result),
+            Err(_) => {
+                // the detached thread may still be executing the JIT-ed code
+                std::mem::forget(execution_engine);
+                Err("execution timed out".to_string())
+            }
+        }
+    }
+
+    /// JIT-run `main`, passing `args` as its parameters. The arity of `main` must
+    /// match `args.len()`; zero- and one-argument entry points are supported.
+    pub fn jit_run_with_args(&self, args: &[i32]) -> Result<i32, String> {
+        let main = self.module.get_function("main").ok_or("module has no main function")?;
+        let arity = main.count_params() as usize;
+        if arity != args.len() {
+            return Err(format!("main takes {} argument(s) but {} were given", arity, args.len()));
+        }
+
+        let execution_engine = self.verified_execution_engine()?;
+        unsafe {
+            match args {
+                [] => {
+                    let main: inkwell::execution_engine::JitFunction<unsafe extern "C" fn() -> i32> =
+                        execution_engine.get_function("main").map_err(|e| e.to_string())?;
+                    Ok(main.call())
+                }
+                [arg] => {
+                    let main: inkwell::execution_engine::JitFunction<unsafe extern "C" fn(i32) -> i32> =
+                        execution_engine.get_function("main").map_err(|e| e.to_string())?;
+                    Ok(main.call(*arg))
+                }
+                _ => Err(format!("cannot JIT a main taking {} arguments; at most 1 is supported", args.len())),
+            }
         }
     }
 
-    /// Write object file for a given target triple (e.g., "wasm32-unknown-unknown" or default triple)
-    pub fn write_target_file(&self, file_name: &str, target_triple: &str) {
-        let target = Target::from_triple(target_triple).expect("target from triple");
+    /// Compile the module to an object file for `target_triple`, returned in memory.
+    pub fn emit_object(&self, target_triple: &str, config: &TargetConfig) -> Result<Vec<u8>, String> {
+        self.emit_file(target_triple, config, FileType::Object)
+    }
+
+    /// Compile the module to assembly text for `target_triple`.
+    pub fn emit_assembly(&self, target_triple: &str, config: &TargetConfig) -> Result<String, String> {
+        let bytes = self.emit_file(target_triple, config, FileType::Assembly)?;
+        String::from_utf8(bytes).map_err(|e| format!("assembly output is not UTF-8: {}", e))
+    }
+
+    fn emit_file(&self, target_triple: &str, config: &TargetConfig, file_type: FileType) -> Result<Vec<u8>, String> {
+        let triple = TargetTriple::create(target_triple);
+        let target = Target::from_triple(&triple).map_err(|e| e.to_string())?;
         let machine = target
             .create_target_machine(
-                target_triple,
-                "generic",
-                "",
-                OptimizationLevel::Default,
-                RelocMode::Default,
-                CodeModel::Default,
+                &triple,
+                &config.cpu,
+                &config.features,
+                config.opt_level,
+                config.reloc_mode,
+                config.code_model,
             )
-            .expect("create target machine");
-        machine.write_to_file(&self.module, FileType::Object, std::path::Path::new(file_name)).expect("write file");
+            .ok_or_else(|| format!("cannot create a target machine for {}", target_triple))?;
+        let buffer = machine
+            .write_to_memory_buffer(&self.module, file_type)
+            .map_err(|e| e.to_string())?;
+        Ok(buffer.as_slice().to_vec())
+    }
+
+    /// Write an object file for `target_triple` (the host's, or e.g. `wasm32-unknown-unknown`).
+    pub fn write_target_file(&self, file_name: &str, target_triple: &str, config: &TargetConfig) -> Result<(), String> {
+        let bytes = self.emit_object(target_triple, config)?;
+        std::fs::write(file_name, bytes).map_err(|e| format!("cannot write {}: {}", file_name, e))
+    }
+
+    /// Write a `.s` assembly file for `target_triple`, for reading the
+    /// generated code or assembling it separately.
+    pub fn write_asm_file(&self, file_name: &str, target_triple: &str, config: &TargetConfig) -> Result<(), String> {
+        let text = self.emit_assembly(target_triple, config)?;
+        std::fs::write(file_name, text).map_err(|e| format!("cannot write {}: {}", file_name, e))
+    }
+
+    /// Write the module as textual LLVM IR (a `.ll` file) to `path`.
+    pub fn write_ir_file(&self, path: &str) -> Result<(), String> {
+        self.module
+            .print_to_file(path)
+            .map_err(|e| format!("cannot write {}: {}", path, e.to_string().trim_end()))
+    }
+
+    /// Write a loadable `.wasm` module to `path`. LLVM only produces a
+    /// relocatable wasm object, so this emits one next to `path` and links it
+    /// with `wasm-ld` (from LLD), which must be on `PATH`. Every function is
+    /// exported and there is no start function; call `main` from the host.
+    pub fn emit_wasm(&self, path: &str) -> Result<(), String> {
+        let object = self.emit_object("wasm32-unknown-unknown", &TargetConfig::default())?;
+        let object_path = format!("{}.o", path);
+        std::fs::write(&object_path, object).map_err(|e| format!("cannot write {}: {}", object_path, e))?;
+
+        let status = std::process::Command::new("wasm-ld")
+            .args(["--no-entry", "--export-all", "-o", path, &object_path])
+            .status();
+        let _ = std::fs::remove_file(&object_path);
+        match status {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("wasm-ld failed with {}", status)),
+            Err(e) => Err(format!("cannot run wasm-ld (is LLD installed?): {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::semantic::SemanticAnalyzer;
+
+    fn parse(src: &str) -> Program {
+        Parser::new(Lexer::new(src.to_string()).tokenize().unwrap()).parse().unwrap()
+    }
+
+    /// Compile `src` as written, without the semantic pass.
+    fn compile<'ctx>(context: &'ctx Context, src: &str) -> Result<LLVMCodegen<'ctx>, CodegenError> {
+        let mut codegen = LLVMCodegen::new(context, "test");
+        codegen.compile_program(&parse(src))?;
+        Ok(codegen)
+    }
+
+    /// Analyze, compile and verify `src`, then run its `main` in the JIT.
+    fn run(src: &str) -> i32 {
+        let mut program = parse(src);
+        SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.compile_program(&program).unwrap();
+        codegen.verify().unwrap();
+        codegen.jit_run().unwrap()
+    }
+
+    #[test]
+    fn unknown_variable_error_points_at_its_line() {
+        let context = Context::create();
+        let err = compile(&context, "let a = 1;\nlet b = a + 1;\nlet c = b * missing;").err().unwrap();
+        assert_eq!(err.message, "unknown variable missing");
+        assert_eq!(err.span, Some(Span::new(3, 13, 3, 20)));
+        assert_eq!(err.to_string(), "3:13: unknown variable missing");
+    }
+
+    #[test]
+    fn temporaries_are_numbered_deterministically() {
+        let src = "fn add(a: i32, b: i32) -> i32 { return a + b; } let x = 2; let y = add(x, 3) * x - 1;";
+        let ir = || {
+            let context = Context::create();
+            let codegen = compile(&context, src).ok().unwrap();
+            codegen.module.print_to_string().to_string()
+        };
+        let first = ir();
+        assert!(first.contains("%addtmp.0 = add i32"), "{}", first);
+        assert!(first.contains("%calltmp."), "{}", first);
+        assert_eq!(first, ir());
+    }
+
+    #[test]
+    fn returns_inside_loops_and_nested_ifs_verify() {
+        let context = Context::create();
+        let codegen = compile(&context, "fn f(c: i32) -> i32 { while c { return 1; } return 0; }").ok().unwrap();
+        codegen.verify().unwrap();
+        let src = "fn g(a: i32, b: i32) -> i32 {
+            if a > 0 {
+                while b > 0 {
+                    if b == 3 { return 3; } else { if a == b { return 2; } }
+                    b = b - 1;
+                }
+                return 1;
+            }
+            return 0;
+        }
+        return g(5, 4) * 10 + g(2, 2) + g(0, 1);";
+        assert_eq!(run(src), 32);
+    }
+
+    #[test]
+    fn break_leaves_the_loop_early() {
+        assert_eq!(run("let i = 0; while i < 100 { if i == 7 { break; } i = i + 1; } return i;"), 7);
+        assert_eq!(run("let n = 0; do { n = n + 1; if n == 3 { break; } } while true; return n;"), 3);
+    }
+
+    #[test]
+    fn continue_skips_the_rest_of_the_body() {
+        let src = "let i = 0; let odd = 0; while i < 10 { i = i + 1; if i % 2 == 0 { continue; } odd = odd + i; } return odd;";
+        assert_eq!(run(src), 25);
+        let src = "let n = 0; let s = 0; do { n = n + 1; if n == 2 { continue; } s = s + n; } while n < 4; return s;";
+        assert_eq!(run(src), 8);
+    }
+
+    #[test]
+    fn break_outside_a_loop_is_a_codegen_error() {
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        let err = codegen.compile_program(&parse("let a = 1; break;")).unwrap_err();
+        assert_eq!(err.message, "`break` or `continue` outside of a loop");
+        assert_eq!(err.span, Some(Span::new(1, 12, 1, 18)));
+    }
+
+    #[test]
+    fn top_level_tuple_names_are_globals() {
+        let src = "let (a, b) = (2, 2.5); let c = a + 1; fn f() -> i32 { if b * 2.0 == 5.0 { return 5; } return 0; } return f() + a + c;";
+        assert_eq!(run(src), 10);
+    }
+
+    #[test]
+    fn tuple_slots_keep_their_element_types() {
+        let src = "fn main() -> i32 {
+            let (a, b, big) = (1.5, true, 5000000000);
+            let c: f64 = a * 4.0;
+            if b && big / 1000000000 == 5 && c == 6.0 { return 6; }
+            return 0;
+        }";
+        assert_eq!(run(src), 6);
+    }
+
+    #[test]
+    fn main_receives_its_arguments_from_the_jit() {
+        let context = Context::create();
+        let codegen = compile(&context, "fn main(n: i32) -> i32 { return n * 2; }").ok().unwrap();
+        codegen.verify().unwrap();
+        assert_eq!(codegen.jit_run_with_args(&[10]), Ok(20));
+        assert!(codegen.jit_run_with_args(&[]).is_err());
+    }
+
+    #[test]
+    fn programs_that_fail_analysis_still_reach_codegen() {
+        // what `--no-semantic` does: the backend sees the program unchecked
+        let src = "fn add(a: i32, b: i32) -> i32 { return a + b; }\nlet y = add(1);";
+        let mut program = parse(src);
+        assert!(SemanticAnalyzer::new().analyze(&mut program.statements).is_err());
+        let context = Context::create();
+        let err = compile(&context, src).err().unwrap();
+        assert_eq!(err.message, "function add takes 2 argument(s) but 1 were supplied");
+        assert_eq!(err.span.map(|s| s.start_line), Some(2));
+    }
+
+    #[test]
+    fn every_binary_operator_compiles() {
+        for op in BinOp::ALL {
+            let (operands, expected) = match op {
+                BinOp::Add => (("7", "2"), 9),
+                BinOp::Sub => (("7", "2"), 5),
+                BinOp::Mul => (("7", "2"), 14),
+                BinOp::Div => (("7", "2"), 3),
+                BinOp::Mod => (("7", "2"), 1),
+                BinOp::Gt => (("7", "2"), 1),
+                BinOp::Lt => (("7", "2"), 0),
+                BinOp::Eq => (("7", "2"), 0),
+                BinOp::Neq => (("7", "2"), 1),
+                BinOp::Ge => (("7", "2"), 1),
+                BinOp::Le => (("7", "2"), 0),
+                BinOp::And => (("true", "false"), 0),
+                BinOp::Or => (("true", "false"), 1),
+            };
+            let src = format!("fn main() {{ return {} {} {}; }}", operands.0, op, operands.1);
+            let context = Context::create();
+            let codegen = compile(&context, &src).ok().unwrap();
+            codegen.verify().unwrap();
+            assert_eq!(codegen.jit_run(), Ok(expected), "{}", src);
+        }
+    }
+
+    #[test]
+    fn constant_conditions_emit_only_the_taken_branch() {
+        let context = Context::create();
+        let src = "let a = 1; if 0 { a = 5; } else { a = 7; } if 1 - 1 { a = 9; } while 0 { a = 3; }";
+        let codegen = compile(&context, src).ok().unwrap();
+        codegen.verify().unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(!ir.contains("br i1"), "{}", ir);
+        assert!(ir.contains("store i32 7"), "{}", ir);
+        for dead in ["store i32 5", "store i32 9", "store i32 3"] {
+            assert!(!ir.contains(dead), "{}", ir);
+        }
+    }
+
+    #[test]
+    fn object_code_is_emitted_in_memory() {
+        let context = Context::create();
+        let codegen = compile(&context, "let a = 1;").ok().unwrap();
+        let triple = inkwell::targets::TargetMachine::get_default_triple();
+        let bytes = codegen.emit_object(triple.as_str().to_str().unwrap(), &TargetConfig::default()).unwrap();
+        assert!(!bytes.is_empty());
+        if cfg!(target_os = "linux") {
+            assert_eq!(&bytes[..4], b"\x7fELF");
+        }
+        assert!(codegen.emit_object("not-a-real-triple", &TargetConfig::default()).is_err());
+    }
+
+    #[test]
+    fn zero_argument_functions_can_be_called() {
+        assert_eq!(run("fn now() -> i32 { return 5; } fn main() -> i32 { now(); return now(); }"), 5);
+    }
+
+    #[test]
+    fn endless_programs_time_out() {
+        let context = Context::create();
+        let codegen = compile(&context, "fn main() { let i = 1; while i { let j = 2; } return 0; }").ok().unwrap();
+        assert_eq!(codegen.jit_run_result(Some(std::time::Duration::from_millis(200))), Err("execution timed out".to_string()));
+        let codegen = compile(&context, "fn main() { return 7; }").ok().unwrap();
+        assert_eq!(codegen.jit_run_result(Some(std::time::Duration::from_secs(5))), Ok(7));
+    }
+
+    #[test]
+    fn wasm_output_starts_with_the_wasm_header() {
+        let context = Context::create();
+        let codegen = compile(&context, "fn main() -> i32 { return 1; }").ok().unwrap();
+        let object = codegen.emit_object("wasm32-unknown-unknown", &TargetConfig::default()).unwrap();
+        assert_eq!(&object[..8], b"\0asm\x01\0\0\0");
+
+        // linking needs wasm-ld; skip that half where it isn't installed
+        if std::process::Command::new("wasm-ld").arg("--version").output().is_err() {
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("mini-compiler-{}.wasm", std::process::id()));
+        let path = path.to_str().unwrap();
+        codegen.emit_wasm(path).unwrap();
+        let module = std::fs::read(path).unwrap();
+        let _ = std::fs::remove_file(path);
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+    }
+
+    #[test]
+    fn top_level_return_is_the_exit_value() {
+        assert_eq!(run("let a = 3; return a + 4; let b = 1;"), 7);
+    }
+
+    #[test]
+    fn inferred_bool_lets_are_stored_as_i1() {
+        let mut program = parse("let x = 1 < 2; let y = 1 + 2; return y + 1;");
+        SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.compile_program(&program).unwrap();
+        codegen.verify().unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("@x = internal global i1 true"), "{}", ir);
+        assert!(ir.contains("@y = internal global i32 3"), "{}", ir);
+        assert_eq!(codegen.jit_run(), Ok(4));
+    }
+
+    #[test]
+    fn native_executable_preset_links_and_runs() {
+        // needs a system C compiler to link; skip where there is none
+        if std::process::Command::new("cc").arg("--version").output().is_err() {
+            return;
+        }
+        let context = Context::create();
+        let codegen = compile(&context, "let a = 40; return a + 2;").ok().unwrap();
+        let triple = inkwell::targets::TargetMachine::get_default_triple();
+        let object = codegen.emit_object(triple.as_str().to_str().unwrap(), &TargetConfig::native_executable()).unwrap();
+
+        let base = std::env::temp_dir().join(format!("mini-compiler-exe-{}", std::process::id()));
+        let (object_path, exe_path) = (base.with_extension("o"), base.with_extension("out"));
+        std::fs::write(&object_path, object).unwrap();
+        let linked = std::process::Command::new("cc").arg(&object_path).arg("-o").arg(&exe_path).status().unwrap();
+        let status = std::process::Command::new(&exe_path).status();
+        let _ = std::fs::remove_file(&object_path);
+        let _ = std::fs::remove_file(&exe_path);
+        assert!(linked.success());
+        assert_eq!(status.unwrap().code(), Some(42));
+    }
+
+    #[test]
+    fn calls_with_the_wrong_argument_count_are_codegen_errors() {
+        let context = Context::create();
+        let src = "fn add(a: i32, b: i32) -> i32 { return a + b; }\nfn main() -> i32 {\n    return add(1, 2, 3);\n}";
+        let err = compile(&context, src).err().unwrap();
+        assert_eq!(err.message, "function add takes 2 argument(s) but 3 were supplied");
+        assert_eq!(err.span.map(|s| s.start_line), Some(3));
+    }
+
+    #[test]
+    fn invalid_modules_are_not_jitted() {
+        let context = Context::create();
+        let codegen = LLVMCodegen::new(&context, "test");
+        // a `main` whose entry block has no terminator
+        let main = codegen.module.add_function("main", context.i32_type().fn_type(&[], false), None);
+        context.append_basic_block(main, "entry");
+        let err = codegen.jit_run_result(None).unwrap_err();
+        assert!(err.starts_with("module failed verification"), "{}", err);
+        assert!(codegen.jit_run().is_err());
+    }
+
+    #[test]
+    fn modulo_takes_the_remainder() {
+        assert_eq!(run("let x = 7; return x % 3;"), 1);
+        assert_eq!(run("let x = 0 - 7; return x % 3;"), -1);
+    }
+
+    #[test]
+    fn logical_operators_skip_the_right_side_when_decided() {
+        for (condition, expected) in [("false && f()", 0), ("true || f()", 1), ("1 < 2 && 2 < 3", 1), ("false || 1 > 2", 0)] {
+            // `f` never returns, so evaluating it would time out
+            let src = format!("fn f() -> bool {{ while true {{ }} return true; }} fn main() -> i32 {{ if {} {{ return 1; }} return 0; }}", condition);
+            let context = Context::create();
+            let codegen = compile(&context, &src).ok().unwrap();
+            assert_eq!(codegen.jit_run_result(Some(std::time::Duration::from_secs(2))), Ok(expected), "{}", condition);
+        }
+    }
+
+    #[test]
+    fn radix_literals_keep_their_value_end_to_end() {
+        assert_eq!(run("let x = 0xFF; return x;"), 255);
+        assert_eq!(run("return 0xF0 + 0b1111;"), 255);
+    }
+
+    #[test]
+    fn unary_operators_negate_and_invert() {
+        assert_eq!(run("return -5;"), -5);
+        assert_eq!(run("let a = 2; let b = 3; return -(a + b);"), -5);
+        assert_eq!(run("let a = 4; if !(a < 2) { return 2 - -a; } return 0;"), 6);
+        assert_eq!(run("if !false { return 1; } return 0;"), 1);
+    }
+
+    #[test]
+    fn do_while_runs_its_body_before_the_check() {
+        assert_eq!(run("let n = 0; do { n = n + 1; } while false; return n;"), 1);
+        assert_eq!(run("let n = 0; do { n = n + 1; if n < 3 { continue; } break; } while true; return n;"), 3);
+        assert_eq!(run("let n = 5; do { n = n - 1; } while n > 0; return n;"), 0);
+    }
+
+    #[test]
+    fn i64_default_mode_widens_untyped_lets() {
+        use crate::semantic::ExprType;
+        let mut program = parse("fn main() -> i32 { let x = 5; let y = x * 1000000000; if y / 1000000000 == 5 { return 1; } return 0; }");
+        let mut sem = SemanticAnalyzer::new();
+        sem.default_int_type = ExprType::I64;
+        sem.analyze(&mut program.statements).unwrap();
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.default_int_type = ExprType::I64;
+        codegen.compile_program(&program).unwrap();
+        codegen.verify().unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("%x = alloca i64") && ir.contains("%y = alloca i64"), "{}", ir);
+        assert_eq!(codegen.jit_run(), Ok(1));
+    }
+
+    #[test]
+    fn demo_program_compiles_and_runs() {
+        let src = "
+            fn add(a: i32, b: i32) -> i32 {
+                return a + b;
+            }
+
+            let x: i32 = 5;
+            let y: i32 = 10;
+            let z: i32 = add(x, y);
+            if z > 10 {
+                z = z + 1;
+            } else {
+                z = z - 1;
+            }
+
+            // while example
+            let i: i32 = 0;
+            // while i < 3 { i = i + 1; } // (if you want to test while)
+            return z;
+        ";
+        assert_eq!(run(src), 16);
+    }
+
+    #[test]
+    fn field_access_is_an_unsupported_codegen_error() {
+        let context = Context::create();
+        let err = compile(&context, "let a = 1; a.b;").err().unwrap();
+        assert!(err.message.contains("not supported"), "{}", err.message);
+    }
+
+    #[test]
+    fn declared_types_map_to_llvm_types() {
+        let src = "fn wide(a: i64, b: bool) -> i64 { if b { return a * 2; } return a; }
+                   fn nothing(n: i32) -> () { return; }
+                   fn truthy(n: i32) -> bool { return n > 0; }
+                   nothing(1);
+                   if truthy(7) && wide(3000000000, true) / 1000000 == 6000 { return 6001; }
+                   return 0;";
+        let mut program = parse(src);
+        SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.compile_program(&program).unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("define i64 @wide(i64 %0, i1 %1)"), "{}", ir);
+        assert!(ir.contains("define void @nothing(i32 %0)"), "{}", ir);
+        assert!(ir.contains("define i1 @truthy(i32 %0)"), "{}", ir);
+        assert_eq!(codegen.jit_run(), Ok(6001));
+
+        let err = compile(&context, "fn n() -> () { } let x = n();").err().unwrap();
+        assert!(err.message.contains("returns no value"), "{}", err.message);
+    }
+
+    #[test]
+    fn main_can_call_functions_declared_below_it() {
+        let src = "fn main() -> i32 { return twice(odd(3)); }
+                   fn twice(n: i32) -> i32 { return n * 2; }
+                   fn odd(n: i32) -> i32 { if n == 0 { return 0; } return even(n - 1); }
+                   fn even(n: i32) -> i32 { if n == 0 { return 1; } return odd(n - 1); }";
+        assert_eq!(run(src), 2);
+        assert_eq!(run("return later(4); fn later(x: i32) -> i32 { return x + 1; }"), 5);
+    }
+
+    #[test]
+    fn returning_blocks_get_no_second_terminator() {
+        let context = Context::create();
+        let codegen = compile(&context, "fn f() -> i32 { return 1; }").ok().unwrap();
+        codegen.verify().unwrap();
+        let src = "fn g(n: i32) -> i32 { if n > 0 { return 1; } else { return 2; } } fn h() { while true { return; } }";
+        compile(&context, src).ok().unwrap().verify().unwrap();
+    }
+
+    #[test]
+    fn if_with_both_arms_returning_has_no_merge_block() {
+        let src = "fn sign(n: i32) -> i32 { if n < 0 { return -1; } else { return 1; } }
+                   fn g(n: i32) -> i32 { while n > 0 { if n > 5 { return 5; } else { break; } } return 0; }
+                   return sign(-4) + g(9);";
+        let context = Context::create();
+        let codegen = compile(&context, src).ok().unwrap();
+        codegen.verify().unwrap();
+        let sign = codegen.module.get_function("sign").unwrap();
+        let blocks: Vec<_> = sign.get_basic_blocks().iter().map(|b| b.get_name().to_str().unwrap().to_string()).collect();
+        assert!(!blocks.iter().any(|name| name.starts_with("after_if")), "{:?}", blocks);
+        assert_eq!(codegen.jit_run(), Ok(4));
+    }
+
+    #[test]
+    fn f64_and_i64_values_compile() {
+        assert_eq!(run("let a: f64 = 1.5; let b = a + 2.25; if b > 3.5 { return 1; } return 0;"), 1);
+        assert_eq!(run("fn half(x: f64) -> f64 { return x / 2; } let h = half(7.0); if h == 3.5 { return 2; } return 0;"), 2);
+        assert_eq!(run("let big: i64 = 5000000000; let r = big - 4999999990; if r == 10 { return 10; } return 0;"), 10);
+        assert_eq!(run("let n: f64 = 0.0 - 2.5; let m = -n; if m >= 2.5 && n < 0.0 { return 3; } return 0;"), 3);
+    }
+
+    #[test]
+    fn only_widening_and_literal_conversions_are_implicit() {
+        let context = Context::create();
+        let codegen = compile(&context, "let a: i64 = 7 > 2; let b: f64 = 2; if b == 2.0 && a == 1 { return 1; } return 0;").ok().unwrap();
+        codegen.verify().unwrap();
+        assert_eq!(codegen.jit_run(), Ok(1));
+
+        for (src, message) in [
+            ("fn f() -> i32 { let x: f64 = 1.5; return x; }", "cannot convert f64 to i32"),
+            ("let n = 2; let x: f64 = n;", "cannot convert i32 to f64"),
+            ("let big: i64 = 5000000000; let x: i32 = big;", "cannot convert i64 to i32"),
+            ("let x: bool = 2;", "cannot convert i32 to bool"),
+        ] {
+            let err = compile(&context, src).err().unwrap();
+            assert_eq!(err.message, message, "{}", src);
+            assert!(err.span.is_some(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn literals_are_compiled_at_their_resolved_type() {
+        // 3 * 1000000000 overflows as i32, so both literals must be i64
+        let src = "fn main() -> i32 { let a: i64 = 3 * 1000000000; if a > 0 { return 1; } return 0; }";
+        assert_eq!(run(src), 1);
+
+        // 7 / 2 divides as f64, both as written and after constant folding
+        let src = "let h: f64 = 7 / 2; if h == 3.5 { return 1; } return 0;";
+        assert_eq!(run(src), 1);
+        let mut program = parse(src);
+        SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        crate::optimiser::fold_constants(&mut program);
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.compile_program(&program).unwrap();
+        codegen.verify().unwrap();
+        assert_eq!(codegen.jit_run(), Ok(1));
+    }
+
+    #[test]
+    fn conditions_branch_on_i1_directly() {
+        use inkwell::values::AnyValue;
+        let mut program = parse(
+            "fn lt(a: i32, b: i32) -> i32 { if a < b { return 1; } return 0; }
+             fn both(a: i32, b: i32) -> bool { let ok = a > 0 && !(b > 0); while ok { return ok; } return false; }
+             let r = lt(1, 2) + lt(2, 1) * 10; if both(1, 0) { r = r + 100; } return r;",
+        );
+        SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.compile_program(&program).unwrap();
+        codegen.verify().unwrap();
+        let lt = codegen.module.get_function("lt").unwrap().print_to_string().to_string();
+        assert_eq!(lt.matches("icmp").count(), 1, "{}", lt);
+        assert!(!lt.contains("zext"), "{}", lt);
+        let both = codegen.module.get_function("both").unwrap().print_to_string().to_string();
+        assert!(both.contains("alloca i1"), "{}", both);
+        assert!(!both.contains("zext") && !both.contains("icmp ne"), "{}", both);
+        assert_eq!(codegen.jit_run(), Ok(101));
+    }
+
+    #[test]
+    fn functions_read_and_write_top_level_globals() {
+        let mut program = parse(
+            "let base = 40; let scale: i64 = 2; fn seed() -> i32 { return 3; } let s = seed();
+             fn get() -> i32 { s = s + 1; return base + s; }
+             let first = get(); return first + get() - 1;",
+        );
+        SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.compile_program(&program).unwrap();
+        codegen.verify().unwrap();
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("@base = internal global i32 40"), "{}", ir);
+        assert!(ir.contains("@scale = internal global i64 2"), "{}", ir);
+        // not a constant, so stored when `main` starts
+        assert!(ir.contains("@s = internal global i32 0"), "{}", ir);
+        assert_eq!(codegen.jit_run(), Ok(44 + 45 - 1));
+    }
+
+    #[test]
+    fn recursive_factorial_runs() {
+        assert_eq!(run("return fact(5); fn fact(n: i32) -> i32 { if n <= 1 { return 1; } return n * fact(n - 1); }"), 120);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_writes_each_value_on_its_own_line() {
+        use std::os::fd::AsRawFd;
+        unsafe extern "C" {
+            fn dup(fd: i32) -> i32;
+            fn dup2(src: i32, dst: i32) -> i32;
+            fn fflush(stream: *mut u8) -> i32;
+        }
+        let mut program = parse("print(42); let x = 7; print(x * 2); return 0;");
+        let mut sem = SemanticAnalyzer::new();
+        sem.analyze(&mut program.statements).unwrap();
+        assert!(sem.warnings.is_empty(), "{:?}", sem.warnings);
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.compile_program(&program).unwrap();
+
+        // point stdout at a file while the JIT-ed code runs
+        let path = std::env::temp_dir().join(format!("mini-compiler-print-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let result = unsafe {
+            let saved = dup(1);
+            dup2(file.as_raw_fd(), 1);
+            let result = codegen.jit_run();
+            fflush(std::ptr::null_mut());
+            dup2(saved, 1);
+            result
+        };
+        let out = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(result, Ok(0));
+        assert_eq!(out, "42\n14\n");
+
+        // a user-defined `print` replaces the builtin
+        assert_eq!(run("fn print(n: i32) -> i32 { return n + 1; } return print(1);"), 2);
+    }
+
+    #[test]
+    fn signed_integers_use_signed_instructions() {
+        use inkwell::values::AnyValue;
+        let context = Context::create();
+        let src = "fn f(a: i32, b: i32) -> i32 { if a < b { return a / b; } return a % b; } return f(-7, 2);";
+        let codegen = compile(&context, src).ok().unwrap();
+        codegen.verify().unwrap();
+        let ir = codegen.module.get_function("f").unwrap().print_to_string().to_string();
+        assert!(ir.contains("sdiv") && ir.contains("srem") && ir.contains("icmp slt"), "{}", ir);
+        assert!(!ir.contains("udiv") && !ir.contains("urem") && !ir.contains("icmp ult"), "{}", ir);
+        assert_eq!(codegen.jit_run(), Ok(-3));
+    }
+
+    #[test]
+    fn write_ir_file_writes_the_module() {
+        let context = Context::create();
+        let src = "fn add(a: i32, b: i32) -> i32 { return a + b; } let x: i32 = 5; return add(x, 10);";
+        let codegen = compile(&context, src).ok().unwrap();
+        let path = std::env::temp_dir().join(format!("write_ir_{}.ll", std::process::id()));
+        let path = path.to_str().unwrap();
+        codegen.write_ir_file(path).unwrap();
+        let ir = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(ir.contains("define i32 @add("), "{}", ir);
+        let err = codegen.write_ir_file("/nonexistent-dir/out.ll").unwrap_err();
+        assert!(err.starts_with("cannot write /nonexistent-dir/out.ll"), "{}", err);
+    }
+
+    #[test]
+    fn write_asm_file_emits_assembly() {
+        let context = Context::create();
+        let codegen = compile(&context, "fn add(a: i32, b: i32) -> i32 { return a + b; } return add(5, 10);")
+            .ok()
+            .unwrap();
+        let triple = inkwell::targets::TargetMachine::get_default_triple();
+        let triple = triple.as_str().to_str().unwrap();
+        let path = std::env::temp_dir().join(format!("asm_out_{}.s", std::process::id()));
+        let path = path.to_str().unwrap();
+        codegen.write_asm_file(path, triple, &TargetConfig::default()).unwrap();
+        let asm = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(asm.contains("add:") && (asm.contains("ret") || asm.contains("bx\tlr")), "{}", asm);
+        if triple.starts_with("x86_64") {
+            assert!(asm.contains("addl") || asm.contains("leal"), "{}", asm);
+        }
+        // object output is unaffected
+        assert!(codegen.emit_object(triple, &TargetConfig::default()).unwrap().starts_with(b"\x7fELF"));
+    }
+
+    #[test]
+    fn jit_run_returns_mains_result() {
+        let context = Context::create();
+        let src = "fn main() -> i32 { let s = 0; let i = 1; while i <= 10 { s = s + i; i = i + 1; } return s; }";
+        let codegen = compile(&context, src).ok().unwrap();
+        assert_eq!(codegen.jit_run(), Ok(55));
+    }
+
+    #[test]
+    fn verify_rejects_a_block_without_terminator() {
+        let context = Context::create();
+        let codegen = compile(&context, "return 1;").ok().unwrap();
+        assert_eq!(codegen.verify(), Ok(()));
+        let broken = codegen.module.add_function("broken", context.i32_type().fn_type(&[], false), None);
+        codegen.builder.position_at_end(context.append_basic_block(broken, "entry"));
+        codegen.builder.build_alloca(context.i32_type(), "x").unwrap();
+        let err = codegen.verify().unwrap_err();
+        let prefix = "module failed verification: ";
+        assert!(err.starts_with(prefix) && err.len() > prefix.len(), "{}", err);
+        assert!(codegen.jit_run().is_err());
+    }
+
+    #[test]
+    fn codegen_failures_are_errors_not_panics() {
+        for src in ["return nope + 1;", "nope = 2;", "return missing(1);", "fn f() -> () { } let x = f();", "let t = (1, 2);"] {
+            let context = Context::create();
+            let err = compile(&context, src).err().unwrap();
+            assert!(err.span.is_some() && !err.message.is_empty(), "{}: {}", src, err);
+        }
     }
 }
@@ -1,3 +1,8 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::Span;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenKind {
     // Keywords
@@ -5,19 +10,35 @@ pub enum TokenKind {
     If,
     Else,
     While,
+    Do,
+    For,
     Fn,
     Return,
+    Break,
+    Continue,
 
     // Identifiers and literals
     Ident,
     Number,
+    /// `true` or `false`; the token's `value` holds which.
+    Bool,
 
     // Operators
     Plus,
     Minus,
     Star,
     Slash,
+    Percent,
     Eq,
+    EqEq,
+    NotEq,
+    Gt,
+    Lt,
+    GtEq,
+    LtEq,
+    AndAnd,
+    OrOr,
+    Bang,
 
     // Symbols
     LParen,
@@ -25,28 +46,156 @@ pub enum TokenKind {
     LBrace,
     RBrace,
     Comma,
+    Dot,
+    Colon,
     Semicolon,
+    Arrow,
+
+    /// A keyword or operator registered through `LexerExtensions`. The
+    /// parser does not know about these.
+    Custom(String),
 
     // End of input
     EOF,
 }
 
+impl TokenKind {
+    pub fn is_keyword(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::Let
+                | TokenKind::If
+                | TokenKind::Else
+                | TokenKind::While
+                | TokenKind::Do
+                | TokenKind::For
+                | TokenKind::Fn
+                | TokenKind::Return
+                | TokenKind::Break
+                | TokenKind::Continue
+        )
+    }
+
+    pub fn is_operator(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::Plus
+                | TokenKind::Minus
+                | TokenKind::Star
+                | TokenKind::Slash
+                | TokenKind::Percent
+                | TokenKind::Eq
+                | TokenKind::EqEq
+                | TokenKind::NotEq
+                | TokenKind::Gt
+                | TokenKind::Lt
+                | TokenKind::GtEq
+                | TokenKind::LtEq
+                | TokenKind::AndAnd
+                | TokenKind::OrOr
+                | TokenKind::Bang
+        )
+    }
+
+    pub fn is_literal(&self) -> bool {
+        matches!(self, TokenKind::Number | TokenKind::Bool)
+    }
+
+    /// Binding power of a binary operator; higher binds tighter. `None` for
+    /// tokens that are not binary operators (including `=`, which is a statement).
+    pub fn precedence(&self) -> Option<u8> {
+        match self {
+            TokenKind::Star | TokenKind::Slash | TokenKind::Percent => Some(5),
+            TokenKind::Plus | TokenKind::Minus => Some(4),
+            TokenKind::EqEq
+            | TokenKind::NotEq
+            | TokenKind::Gt
+            | TokenKind::Lt
+            | TokenKind::GtEq
+            | TokenKind::LtEq => Some(3),
+            TokenKind::AndAnd => Some(2),
+            TokenKind::OrOr => Some(1),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub kind: TokenKind,
+    /// text of the token; for a `0x` or `0b` literal, its value in decimal
     pub value: String,
+    pub line: usize,
+    pub col: usize,
+    /// number of source characters the token covers
+    pub len: usize,
+}
+
+impl Token {
+    /// Token whose value is its source text.
+    pub fn new(kind: TokenKind, value: String, line: usize, col: usize) -> Self {
+        let len = value.chars().count();
+        Token { kind, value, line, col, len }
+    }
+
+    /// Source span covered by this token (tokens never span lines).
+    pub fn span(&self) -> Span {
+        Span::new(self.line, self.col, self.line, self.col + self.len)
+    }
+}
+
+/// Source text that cannot be split into tokens, positioned where the
+/// offending token or comment starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub message: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl LexError {
+    fn new(message: impl Into<String>, line: usize, col: usize) -> Self {
+        LexError { message: message.into(), line, col }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.message)
+    }
+}
+
+/// Extra syntax for prototyping language extensions without editing the
+/// lexer. Built-in keywords and operators always win over these.
+#[derive(Debug, Clone, Default)]
+pub struct LexerExtensions {
+    /// Identifier spellings to lex as the given kind, e.g. `"loop"`.
+    pub keywords: HashMap<String, TokenKind>,
+    /// Single characters to lex as the given kind, e.g. `'@'`. Letters, digits
+    /// and `_` start identifiers and numbers, so they cannot be operators.
+    pub operators: HashMap<char, TokenKind>,
 }
 
 pub struct Lexer {
     source: Vec<char>,
     pos: usize,
+    line: usize,
+    col: usize,
+    extensions: LexerExtensions,
 }
 
 impl Lexer {
     pub fn new(source: String) -> Self {
+        Self::with_extensions(source, LexerExtensions::default())
+    }
+
+    pub fn with_extensions(source: String, extensions: LexerExtensions) -> Self {
         Lexer {
             source: source.chars().collect(),
             pos: 0,
+            line: 1,
+            col: 1,
+            extensions,
         }
     }
 
@@ -56,59 +205,144 @@ impl Lexer {
 
     fn next(&mut self) -> Option<char> {
         let ch = self.source.get(self.pos).cloned();
-        self.pos += 1;
+        if let Some(c) = ch {
+            self.pos += 1;
+            if c == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
         ch
     }
 
-    fn skip_whitespace(&mut self) {
+    fn peek_next(&self) -> Option<char> {
+        self.source.get(self.pos + 1).cloned()
+    }
+
+    /// Skip whitespace, `//` line comments and (nested) `/* */` block comments.
+    fn skip_whitespace(&mut self) -> Result<(), LexError> {
         while let Some(ch) = self.peek() {
             if ch.is_whitespace() {
-                self.pos += 1;
+                self.next();
+            } else if ch == '/' && self.peek_next() == Some('/') {
+                // the newline itself is left for the whitespace branch
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.next();
+                }
+            } else if ch == '/' && self.peek_next() == Some('*') {
+                self.skip_block_comment()?;
             } else {
                 break;
             }
         }
+        Ok(())
+    }
+
+    /// Skip a `/* ... */` comment starting at the current position. Block
+    /// comments nest, so `/* a /* b */ c */` is a single comment.
+    fn skip_block_comment(&mut self) -> Result<(), LexError> {
+        let (line, col) = (self.line, self.col);
+        let mut depth = 0;
+        loop {
+            match (self.peek(), self.peek_next()) {
+                (Some('/'), Some('*')) => {
+                    self.next();
+                    self.next();
+                    depth += 1;
+                }
+                (Some('*'), Some('/')) => {
+                    self.next();
+                    self.next();
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                (Some(_), _) => {
+                    self.next();
+                }
+                (None, _) => return Err(LexError::new("unterminated block comment", line, col)),
+            }
+        }
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
+    /// Split the whole source into tokens, ending with an `EOF` token. Stops
+    /// at the first error.
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, LexError> {
         let mut tokens = Vec::new();
 
-        while let Some(ch) = self.peek() {
-            self.skip_whitespace();
+        loop {
+            self.skip_whitespace()?;
+            let Some(ch) = self.peek() else { break };
+            let (line, col) = (self.line, self.col);
 
             if ch.is_alphabetic() || ch == '_' {
                 tokens.push(self.lex_ident_or_keyword());
             } else if ch.is_ascii_digit() {
-                tokens.push(self.lex_number());
+                tokens.push(self.lex_number()?);
+            } else if let Some(tok) = self.lex_two_char_operator() {
+                tokens.push(tok);
             } else {
-                match self.next().unwrap() {
-                    '+' => tokens.push(Token { kind: TokenKind::Plus, value: "+".to_string() }),
-                    '-' => tokens.push(Token { kind: TokenKind::Minus, value: "-".to_string() }),
-                    '*' => tokens.push(Token { kind: TokenKind::Star, value: "*".to_string() }),
-                    '/' => tokens.push(Token { kind: TokenKind::Slash, value: "/".to_string() }),
-                    '=' => tokens.push(Token { kind: TokenKind::Eq, value: "=".to_string() }),
-                    '(' => tokens.push(Token { kind: TokenKind::LParen, value: "(".to_string() }),
-                    ')' => tokens.push(Token { kind: TokenKind::RParen, value: ")".to_string() }),
-                    '{' => tokens.push(Token { kind: TokenKind::LBrace, value: "{".to_string() }),
-                    '}' => tokens.push(Token { kind: TokenKind::RBrace, value: "}".to_string() }),
-                    ',' => tokens.push(Token { kind: TokenKind::Comma, value: ",".to_string() }),
-                    ';' => tokens.push(Token { kind: TokenKind::Semicolon, value: ";".to_string() }),
-                    _ => panic!("Unexpected character '{}'", ch),
-                }
+                let kind = match self.next().unwrap() {
+                    '+' => TokenKind::Plus,
+                    '-' => TokenKind::Minus,
+                    '*' => TokenKind::Star,
+                    '/' => TokenKind::Slash,
+                    '%' => TokenKind::Percent,
+                    '=' => TokenKind::Eq,
+                    '>' => TokenKind::Gt,
+                    '<' => TokenKind::Lt,
+                    '!' => TokenKind::Bang,
+                    '(' => TokenKind::LParen,
+                    ')' => TokenKind::RParen,
+                    '{' => TokenKind::LBrace,
+                    '}' => TokenKind::RBrace,
+                    ',' => TokenKind::Comma,
+                    '.' => TokenKind::Dot,
+                    ':' => TokenKind::Colon,
+                    ';' => TokenKind::Semicolon,
+                    other => match self.extensions.operators.get(&other) {
+                        Some(kind) => kind.clone(),
+                        None => return Err(LexError::new(format!("unexpected character '{}'", ch), line, col)),
+                    },
+                };
+                tokens.push(Token::new(kind, ch.to_string(), line, col));
             }
         }
 
-        tokens.push(Token { kind: TokenKind::EOF, value: "".to_string() });
-        tokens
+        tokens.push(Token::new(TokenKind::EOF, "".to_string(), self.line, self.col));
+        Ok(tokens)
+    }
+
+    /// Maximal munch for two-character operators: `==` is one token, not two
+    /// `=`. Returns `None` (consuming nothing) if none starts here. A lone `&`
+    /// or `|` is not an operator.
+    fn lex_two_char_operator(&mut self) -> Option<Token> {
+        let (line, col) = (self.line, self.col);
+        let kind = match (self.peek()?, self.peek_next()?) {
+            ('=', '=') => TokenKind::EqEq,
+            ('!', '=') => TokenKind::NotEq,
+            ('>', '=') => TokenKind::GtEq,
+            ('<', '=') => TokenKind::LtEq,
+            ('&', '&') => TokenKind::AndAnd,
+            ('|', '|') => TokenKind::OrOr,
+            ('-', '>') => TokenKind::Arrow,
+            _ => return None,
+        };
+        let value: String = [self.next()?, self.next()?].iter().collect();
+        Some(Token::new(kind, value, line, col))
     }
 
     fn lex_ident_or_keyword(&mut self) -> Token {
+        let (line, col) = (self.line, self.col);
         let mut ident = String::new();
 
         while let Some(ch) = self.peek() {
             if ch.is_alphanumeric() || ch == '_' {
                 ident.push(ch);
-                self.pos += 1;
+                self.next();
             } else {
                 break;
             }
@@ -119,24 +353,222 @@ impl Lexer {
             "if" => TokenKind::If,
             "else" => TokenKind::Else,
             "while" => TokenKind::While,
+            "do" => TokenKind::Do,
+            "for" => TokenKind::For,
             "fn" => TokenKind::Fn,
             "return" => TokenKind::Return,
-            _ => TokenKind::Ident,
+            "break" => TokenKind::Break,
+            "continue" => TokenKind::Continue,
+            "true" | "false" => TokenKind::Bool,
+            word => self.extensions.keywords.get(word).cloned().unwrap_or(TokenKind::Ident),
         };
 
-        Token { kind, value: ident }
+        Token::new(kind, ident, line, col)
     }
 
-    fn lex_number(&mut self) -> Token {
+    fn lex_number(&mut self) -> Result<Token, LexError> {
+        let (line, col) = (self.line, self.col);
+        if let Some(radix) = self.radix_prefix() {
+            return self.lex_radix_number(radix, line, col);
+        }
         let mut num = String::new();
         while let Some(ch) = self.peek() {
             if ch.is_ascii_digit() {
                 num.push(ch);
-                self.pos += 1;
+                self.next();
             } else {
                 break;
             }
         }
-        Token { kind: TokenKind::Number, value: num }
+        // fractional part: `3.25` is a float literal, `3.` is not
+        if self.peek() == Some('.') && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
+            num.push('.');
+            self.next();
+            while let Some(ch) = self.peek().filter(|c| c.is_ascii_digit()) {
+                num.push(ch);
+                self.next();
+            }
+        }
+        Ok(Token::new(TokenKind::Number, num, line, col))
+    }
+
+    /// Radix of a `0x` (hex) or `0b` (binary) prefix at the current position.
+    fn radix_prefix(&self) -> Option<u32> {
+        match (self.peek()?, self.peek_next()?) {
+            ('0', 'x') => Some(16),
+            ('0', 'b') => Some(2),
+            _ => None,
+        }
+    }
+
+    /// Lex a prefixed literal such as `0xFF` or `0b1010`. The token's value is
+    /// the decoded decimal (`255`), so later stages see only one spelling.
+    fn lex_radix_number(&mut self, radix: u32, line: usize, col: usize) -> Result<Token, LexError> {
+        let mut num: String = [self.next().unwrap(), self.next().unwrap()].iter().collect();
+        while let Some(ch) = self.peek().filter(|c| c.is_ascii_alphanumeric()) {
+            if !ch.is_digit(radix) {
+                let message = format!("invalid digit '{}' in {}", ch, radix_name(radix));
+                return Err(LexError::new(message, self.line, self.col));
+            }
+            num.push(ch);
+            self.next();
+        }
+        if num.len() == 2 {
+            return Err(LexError::new(format!("expected digits after '{}' in {}", num, radix_name(radix)), line, col));
+        }
+        let Ok(value) = i128::from_str_radix(&num[2..], radix) else {
+            return Err(LexError::new(format!("literal {} is too large", num), line, col));
+        };
+        let len = num.chars().count();
+        Ok(Token { kind: TokenKind::Number, value: value.to_string(), line, col, len })
+    }
+}
+
+fn radix_name(radix: u32) -> &'static str {
+    if radix == 16 { "hexadecimal literal" } else { "binary literal" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(src: &str) -> Result<Vec<Token>, LexError> {
+        Lexer::new(src.to_string()).tokenize()
+    }
+
+    #[test]
+    fn unterminated_block_comment_is_an_error() {
+        let err = lex("let a = 1;\n  /* x /* y */ ").unwrap_err();
+        assert_eq!(err, LexError::new("unterminated block comment", 2, 3));
+        assert_eq!(err.to_string(), "2:3: unterminated block comment");
+    }
+
+    #[test]
+    fn radix_literals_are_decoded_to_decimal() {
+        let toks = lex("0xFF 0b1010").unwrap();
+        assert_eq!((toks[0].value.as_str(), toks[1].value.as_str()), ("255", "10"));
+        // spans still cover the literal as written
+        assert_eq!(toks[0].span(), Span::new(1, 1, 1, 5));
+        assert_eq!(toks[1].span(), Span::new(1, 6, 1, 12));
+    }
+
+    #[test]
+    fn malformed_radix_literals_are_errors() {
+        assert_eq!(lex("0x;").unwrap_err(), LexError::new("expected digits after '0x' in hexadecimal literal", 1, 1));
+        assert_eq!(lex("x = 0b102;").unwrap_err(), LexError::new("invalid digit '2' in binary literal", 1, 9));
+        let too_large = format!("0x{}", "F".repeat(40));
+        assert_eq!(lex(&too_large).unwrap_err().message, format!("literal {} is too large", too_large));
+    }
+
+    #[test]
+    fn unexpected_character_is_an_error() {
+        assert_eq!(lex("let a = 1 # 2;").unwrap_err(), LexError::new("unexpected character '#'", 1, 11));
+    }
+
+    #[test]
+    fn array_brackets_are_not_tokens_yet() {
+        // no array literals or indexing to fold until `[` and `]` lex
+        assert_eq!(lex("[10, 20, 30][1]").unwrap_err(), LexError::new("unexpected character '['", 1, 1));
+    }
+
+    #[test]
+    fn tokens_record_their_line_and_column() {
+        let toks = lex("let x = 1;\n  while x {\n\tx = x - 1; }").unwrap();
+        let at = |i: usize| (toks[i].value.as_str(), toks[i].line, toks[i].col);
+        assert_eq!(at(0), ("let", 1, 1));
+        assert_eq!(at(3), ("1", 1, 9));
+        assert_eq!(at(5), ("while", 2, 3));
+        assert_eq!(at(8), ("x", 3, 2));
+        assert_eq!(toks.last().unwrap().kind, TokenKind::EOF);
+    }
+
+    #[test]
+    fn line_comments_produce_no_tokens() {
+        let toks = lex("// on its own line\nlet a = 6 / 2; // trailing\n// at the end").unwrap();
+        let kinds: Vec<_> = toks.iter().map(|t| t.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Let,
+                TokenKind::Ident,
+                TokenKind::Eq,
+                TokenKind::Number,
+                TokenKind::Slash,
+                TokenKind::Number,
+                TokenKind::Semicolon,
+                TokenKind::EOF,
+            ]
+        );
+        assert_eq!((toks[0].line, toks[0].col), (2, 1));
+    }
+
+    #[test]
+    fn token_kinds_classify_themselves() {
+        assert!(TokenKind::Plus.is_operator());
+        assert!(TokenKind::Let.is_keyword());
+        assert!(TokenKind::Number.is_literal());
+        assert!(!TokenKind::Ident.is_keyword() && !TokenKind::LParen.is_operator());
+        assert!(TokenKind::Star.precedence() > TokenKind::Plus.precedence());
+        assert!(TokenKind::AndAnd.precedence() > TokenKind::OrOr.precedence());
+        assert_eq!(TokenKind::Eq.precedence(), None);
+    }
+
+    #[test]
+    fn block_comments_span_lines_and_nest() {
+        let toks = lex("let a = 1; /* one\n two */ let b = 2;").unwrap();
+        assert_eq!(toks.len(), 11);
+        assert_eq!((toks[5].value.as_str(), toks[5].line, toks[5].col), ("let", 2, 9));
+
+        let toks = lex("let b = /* x /* y */ z */ 2;").unwrap();
+        let values: Vec<_> = toks.iter().map(|t| t.value.as_str()).collect();
+        assert_eq!(values, vec!["let", "b", "=", "2", ";", ""]);
+    }
+
+    #[test]
+    fn comparison_operators_lex_by_maximal_munch() {
+        use TokenKind::*;
+        let kinds = |src: &str| lex(src).unwrap().into_iter().map(|t| t.kind).collect::<Vec<_>>();
+        assert_eq!(kinds("a == b"), vec![Ident, EqEq, Ident, EOF]);
+        assert_eq!(kinds("a = b"), vec![Ident, Eq, Ident, EOF]);
+        assert_eq!(kinds("a != b < c > d === e"), vec![Ident, NotEq, Ident, Lt, Ident, Gt, Ident, EqEq, Eq, Ident, EOF]);
+        assert_eq!(lex("x != 1").unwrap()[1].span(), Span::new(1, 3, 1, 5));
+    }
+
+    #[test]
+    fn true_and_false_lex_as_bools() {
+        let toks = lex("true false trueish").unwrap();
+        let kinds: Vec<_> = toks.iter().map(|t| t.kind.clone()).collect();
+        assert_eq!(kinds, vec![TokenKind::Bool, TokenKind::Bool, TokenKind::Ident, TokenKind::EOF]);
+        assert_eq!(toks[1].value, "false");
+        assert!(TokenKind::Bool.is_literal());
+    }
+
+    #[test]
+    fn arrow_needs_adjacent_minus_and_greater() {
+        use TokenKind::*;
+        let kinds: Vec<_> = lex("a->b - > -x").unwrap().into_iter().map(|t| t.kind).collect();
+        assert_eq!(kinds, [Ident, Arrow, Ident, Minus, Gt, Minus, Ident, EOF]);
+    }
+
+    #[test]
+    fn extensions_add_keywords_and_operators() {
+        let mut extensions = LexerExtensions::default();
+        extensions.keywords.insert("loop".to_string(), TokenKind::Custom("loop".to_string()));
+        extensions.keywords.insert("let".to_string(), TokenKind::Custom("shadowed".to_string()));
+        extensions.operators.insert('@', TokenKind::Custom("at".to_string()));
+        extensions.operators.insert('+', TokenKind::Custom("shadowed".to_string()));
+        let toks = Lexer::with_extensions("loop let loops @ +".to_string(), extensions).tokenize().unwrap();
+        let kinds: Vec<_> = toks.into_iter().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                TokenKind::Custom("loop".to_string()),
+                TokenKind::Let,
+                TokenKind::Ident,
+                TokenKind::Custom("at".to_string()),
+                TokenKind::Plus,
+                TokenKind::EOF,
+            ]
+        );
     }
 }
@@ -0,0 +1,8 @@
+pub mod ast;
+pub mod lexer;
+pub mod parser;
+pub mod semantic;
+pub mod codegen_bytecode;
+pub mod codegen_llvm;
+pub mod optimiser;
+pub mod utils;
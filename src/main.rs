@@ -1,17 +1,12 @@
 // src/main.rs
-mod lexer;
-mod parser;
-mod ast;
-mod semantic;
-mod codegen_llvm;
-mod optimiser;
+use mini_rust_compiler::{ast, codegen_llvm, lexer, optimiser, parser, semantic, utils};
 
 use inkwell::context::Context;
-use std::env;
+use inkwell::OptimizationLevel;
+use std::{env, fs, process};
 
-fn main() {
-    // simple demo: use hardcoded source or pass filename
-    let source = r#"
+/// Program compiled when no source file is given on the command line.
+const DEMO_SOURCE: &str = r#"
         fn add(a: i32, b: i32) -> i32 {
             return a + b;
         }
@@ -28,41 +23,172 @@ fn main() {
         // while example
         let i: i32 = 0;
         // while i < 3 { i = i + 1; } // (if you want to test while)
-    "#.to_string();
+    "#;
+
+fn main() {
+    // simple demo: use hardcoded source or pass filename
+    let mut path = None;
+    let mut lint_int_div = false;
+    let mut fail_fast = false;
+    let mut opt_config = optimiser::OptConfig::default();
+    let mut target_config = codegen_llvm::TargetConfig::default();
+    let mut run_semantic = true;
+    let mut dump_symbols = false;
+    let mut default_int_type = semantic::ExprType::I32;
+    let mut opt_level = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--explain" => {
+                let code = args.next().unwrap_or_default();
+                match semantic::DiagnosticKind::from_code(&code) {
+                    Some(kind) => {
+                        println!("{}: {}", kind.code(), kind.explanation());
+                        process::exit(0);
+                    }
+                    None => match semantic::DiagnosticKind::retired_explanation(&code) {
+                        Some(text) => {
+                            println!("{}: {}", code.to_ascii_uppercase(), text);
+                            process::exit(0);
+                        }
+                        None => {
+                            eprintln!("error: unknown diagnostic code `{}`", code);
+                            process::exit(2);
+                        }
+                    },
+                }
+            }
+            "--lint-int-div" => lint_int_div = true,
+            "--fail-fast" => fail_fast = true,
+            "--dump-symbols" => dump_symbols = true,
+            "--default-int" => {
+                default_int_type = match args.next().as_deref() {
+                    Some("i32") => semantic::ExprType::I32,
+                    Some("i64") => semantic::ExprType::I64,
+                    other => {
+                        eprintln!("error: --default-int expects i32 or i64, got {}", other.unwrap_or("nothing"));
+                        process::exit(2);
+                    }
+                }
+            }
+            "--tail-loops" => opt_config.tail_recursion_to_loops = true,
+            "--pic" => target_config = codegen_llvm::TargetConfig::native_executable(),
+            "-O0" => opt_level = Some(OptimizationLevel::None),
+            "-O1" => opt_level = Some(OptimizationLevel::Less),
+            "-O2" => opt_level = Some(OptimizationLevel::Default),
+            "-O3" => opt_level = Some(OptimizationLevel::Aggressive),
+            // debugging aid: send unchecked programs straight to codegen, which
+            // may then fail with codegen errors the analyzer would have caught
+            "--no-semantic" => run_semantic = false,
+            flag if flag.starts_with("--") => {
+                eprintln!("error: unknown flag {}", flag);
+                process::exit(2);
+            }
+            _ => path = Some(arg),
+        }
+    }
+    if let Some(level) = opt_level {
+        opt_config.level = level;
+        target_config.opt_level = level;
+    }
+    let source = match path {
+        Some(path) => fs::read_to_string(&path).unwrap_or_else(|err| {
+            eprintln!("error: cannot read {}: {}", path, err);
+            process::exit(1);
+        }),
+        None => DEMO_SOURCE.to_string(),
+    };
 
     // Lexing & parsing
-    let mut lexer = lexer::Lexer::new(source);
-    let tokens = lexer.tokenize();
+    let mut lexer = lexer::Lexer::new(source.clone());
+    let tokens = match lexer.tokenize() {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            let span = ast::Span::new(err.line, err.col, err.line, err.col + 1);
+            eprintln!("{}", utils::render_snippet(&source, span, &err.message));
+            process::exit(1);
+        }
+    };
 
-    // Update: parser must produce Program (ast::Program). If your parser API differs, change this line.
     let mut parser = parser::Parser::new(tokens);
-    let program = parser.parse(); // expects Program
+    let mut program = match parser.parse() {
+        Ok(program) => program,
+        Err(errors) => {
+            for err in &errors {
+                let span = ast::Span::new(err.line, err.col, err.line, err.col + 1);
+                eprintln!("{}", utils::render_snippet(&source, span, &err.message));
+            }
+            process::exit(1);
+        }
+    };
 
     // Semantic analysis (your implementation)
-    let mut sem = semantic::SemanticAnalyzer::new();
-    sem.analyze(&program);
+    if run_semantic {
+        let mut sem = semantic::SemanticAnalyzer::new();
+        sem.lint_int_div = lint_int_div;
+        sem.fail_fast = fail_fast;
+        sem.default_int_type = default_int_type;
+        let result = sem.analyze(&mut program.statements);
+        for warning in &sem.warnings {
+            let label = format!("warning[{}]", warning.kind.code());
+            eprintln!("{}", utils::render_labeled_snippet(&source, warning.span, &label, &warning.message));
+        }
+        if dump_symbols {
+            for symbol in &sem.symbols {
+                println!("{}", symbol);
+            }
+        }
+        if let Err(errors) = result {
+            for err in &errors {
+                let label = format!("error[{}]", err.kind.code());
+                eprintln!("{}", utils::render_labeled_snippet(&source, err.span, &label, &err.message));
+            }
+            process::exit(1);
+        }
+        if dump_symbols {
+            return;
+        }
+    }
+
+    optimiser::optimise_ast(&mut program, &opt_config);
 
     // Codegen
     let context = Context::create();
     let mut codegen = codegen_llvm::LLVMCodegen::new(&context, "my_module");
-    codegen.compile_program(&program);
+    codegen.default_int_type = default_int_type;
+    codegen.jit_opt_level = opt_config.level;
+    if let Err(err) = codegen.compile_program(&program) {
+        match err.span {
+            Some(span) => eprintln!("{}", utils::render_snippet(&source, span, &err.message)),
+            None => eprintln!("error: {}", err.message),
+        }
+        process::exit(1);
+    }
+
+    if let Err(err) = codegen.verify() {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
 
     // Optional: optimise
-    optimiser::run_llvm_optimizations(&codegen.module);
+    optimiser::run_llvm_optimizations(&codegen.module, &opt_config);
 
     // Emit IR (for debugging)
     codegen.dump_module();
 
     // JIT-run for quick tests (optional)
-    // codegen.jit_run();
+    // println!("JIT main returned {}", codegen.jit_run().unwrap());
 
     // Write an object file for host native
     let default_triple = inkwell::targets::TargetMachine::get_default_triple();
-    let native_triple = default_triple.to_str().unwrap();
-    codegen.write_target_file("output.o", native_triple);
+    let native_triple = default_triple.as_str().to_str().unwrap();
+    if let Err(err) = codegen.write_target_file("output.o", native_triple, &target_config) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
 
     // Also write a wasm object (if your LLVM supports wasm target)
-    // codegen.write_target_file("output_wasm.o", "wasm32-unknown-unknown");
+    // codegen.write_target_file("output_wasm.o", "wasm32-unknown-unknown", &target_config);
 
     println!("Done: generated output.o (and optionally output_wasm.o).");
 }
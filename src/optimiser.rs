@@ -1,21 +1,410 @@
 
+use crate::ast::{BinOp, Expr, ExprKind, Literal, Program, Span, Stmt, StmtKind, Type, UnaryOp};
+use crate::semantic::const_int;
 use inkwell::module::Module;
-use inkwell::passes::PassManager;
-use inkwell::context::Context;
+use inkwell::passes::{PassManager, PassManagerBuilder};
+use inkwell::OptimizationLevel;
+
+/// LLVM's inline cost model charges roughly this much per instruction.
+const INLINE_COST_PER_INSTR: u32 = 5;
+
+/// Options for `run_llvm_optimizations`.
+#[derive(Debug, Clone, Copy)]
+pub struct OptConfig {
+    pub level: OptimizationLevel,
+    /// Callees estimated at fewer than this many instructions are inlined
+    /// automatically at `-O2` and above. `None` disables size-based inlining.
+    pub inline_threshold: Option<u32>,
+    /// Rewrite self-tail-recursive functions into loops (see `rewrite_tail_recursion`).
+    pub tail_recursion_to_loops: bool,
+    /// Upper bound on how many times the function passes run. One pass can
+    /// expose work for another (GVN forwards a load, then instcombine can fold
+    /// it), so above 1 they are rerun until a round changes nothing or the
+    /// bound is reached.
+    pub max_pass_rounds: u32,
+}
+
+impl Default for OptConfig {
+    fn default() -> Self {
+        // 45 instructions matches LLVM's own -O2 inline threshold (225)
+        OptConfig {
+            level: OptimizationLevel::Default,
+            inline_threshold: Some(45),
+            tail_recursion_to_loops: false,
+            max_pass_rounds: 1,
+        }
+    }
+}
+
+/// Run the AST-level transforms enabled in `config`. Call this after semantic
+/// analysis and before codegen.
+pub fn optimise_ast(program: &mut Program, config: &OptConfig) {
+    fold_constants(program);
+    if config.tail_recursion_to_loops {
+        rewrite_tail_recursion(program);
+    }
+}
+
+/// Replace integer arithmetic on literals with its value: `2 + 3 * 4` becomes
+/// `14`. Only `+ - * / %` and negation are folded, since their result is still
+/// an integer; comparisons are left alone so the bytecode VM keeps seeing
+/// booleans. Division by zero and overflow are left for run time. Folding is
+/// done in i32, so an expression the analyzer typed as anything else (the
+/// f64 `7 / 2` in `let h: f64 = 7 / 2;`) is left alone.
+pub fn fold_constants(program: &mut Program) {
+    program.statements.iter_mut().for_each(fold_stmt);
+}
+
+fn fold_stmt(stmt: &mut Stmt) {
+    match &mut stmt.kind {
+        StmtKind::VarDecl { value, .. }
+        | StmtKind::VarDeclTuple { value, .. }
+        | StmtKind::Assignment { value, .. }
+        | StmtKind::ExprStmt(value)
+        | StmtKind::Return(Some(value)) => fold_expr(value),
+        StmtKind::IfStmt { condition, then_branch, else_branch } => {
+            fold_expr(condition);
+            then_branch.iter_mut().for_each(fold_stmt);
+            else_branch.iter_mut().flatten().for_each(fold_stmt);
+        }
+        StmtKind::While { condition, body } | StmtKind::DoWhile { body, condition } => {
+            fold_expr(condition);
+            body.iter_mut().for_each(fold_stmt);
+        }
+        StmtKind::Function { body, .. } => body.iter_mut().for_each(fold_stmt),
+        StmtKind::Return(None) | StmtKind::Break | StmtKind::Continue => {}
+    }
+}
+
+fn fold_expr(expr: &mut Expr) {
+    match &mut expr.kind {
+        ExprKind::Number(_) | ExprKind::Identifier(_) => return,
+        ExprKind::Unary { operand, .. } => fold_expr(operand),
+        ExprKind::Field { base, .. } => {
+            fold_expr(base);
+            return;
+        }
+        ExprKind::Binary { left, right, .. } => {
+            fold_expr(left);
+            fold_expr(right);
+        }
+        ExprKind::Call { args: exprs, .. } | ExprKind::Tuple(exprs) => {
+            exprs.iter_mut().for_each(fold_expr);
+            return;
+        }
+    }
+    let arithmetic = matches!(
+        expr.kind,
+        ExprKind::Unary { op: UnaryOp::Neg, .. }
+            | ExprKind::Binary { operator: BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod, .. }
+    );
+    let folds_as_i32 = expr.ty.is_none_or(|ty| ty == Type::I32);
+    if arithmetic && folds_as_i32 && let Some(n) = const_int(expr) {
+        expr.kind = ExprKind::Number(Literal::Int(n.into()));
+    }
+}
+
+/// Turn self-tail-recursive functions into loops, so deep recursion does not
+/// need a deep stack. A call is rewritten when it is the whole expression of a
+/// `return` in tail position (the last statement of the body, or of a branch of
+/// an `if` that is itself in tail position):
+///
+/// ```text
+/// fn sum(n, acc) {                  fn sum(n, acc) {
+///     if n == 0 {                       while 1 {
+///         return acc;                       if n == 0 {
+///     }                                         return acc;
+///     return sum(n - 1, acc + n);           } else {
+/// }                                             let __tail_0 = n - 1;
+///                                               let __tail_1 = acc + n;
+///                                               n = __tail_0;
+///                                               acc = __tail_1;
+///                                           }
+///                                       }
+///                                   }
+/// ```
+///
+/// Statements after an `if` whose branch always returns are first moved into
+/// its `else`, as above. Paths that fell off the end of the body get an
+/// explicit `return` so they leave the loop: a bare one when the function
+/// returns unit or has no declared result, else `return 0`. Self-calls
+/// anywhere else are left as real calls.
+pub fn rewrite_tail_recursion(program: &mut Program) {
+    for stmt in &mut program.statements {
+        if let StmtKind::Function { name, params, ret_type, body } = &mut stmt.kind {
+            let folded = fold_early_returns(body.clone());
+            if !ends_in_tail_call(&folded, name, params.len()) {
+                continue;
+            }
+            let tail = TailContext { name, params, returns_unit: matches!(ret_type, None | Some(Type::Unit)) };
+            let loop_body = rewrite_tail(folded, &tail, stmt.span);
+            let always = Expr::new(ExprKind::Number(Literal::Int(1)), stmt.span);
+            *body = vec![Stmt { kind: StmtKind::While { condition: always, body: loop_body }, span: stmt.span }];
+        }
+    }
+}
+
+/// Move the statements following an else-less `if` whose branch ends in
+/// `return` into that `if`'s `else`, so the `if` becomes the last statement.
+/// Applied again inside the branches of the resulting tail `if`.
+fn fold_early_returns(mut stmts: Vec<Stmt>) -> Vec<Stmt> {
+    let early_return = stmts.iter().position(|s| {
+        matches!(&s.kind, StmtKind::IfStmt { then_branch, else_branch: None, .. }
+            if matches!(then_branch.last().map(|t| &t.kind), Some(StmtKind::Return(_))))
+    });
+    if let Some(i) = early_return
+        && i + 1 < stmts.len()
+    {
+        let rest = stmts.split_off(i + 1);
+        if let StmtKind::IfStmt { else_branch, .. } = &mut stmts[i].kind {
+            *else_branch = Some(rest);
+        }
+    }
+    if let Some(Stmt { kind: StmtKind::IfStmt { then_branch, else_branch, .. }, .. }) = stmts.last_mut() {
+        *then_branch = fold_early_returns(std::mem::take(then_branch));
+        if let Some(block) = else_branch {
+            *block = fold_early_returns(std::mem::take(block));
+        }
+    }
+    stmts
+}
+
+/// The arguments of `return name(args);` with the right arity, if `stmt` is one.
+fn tail_call_args<'a>(stmt: &'a Stmt, name: &str, arity: usize) -> Option<&'a [Expr]> {
+    match &stmt.kind {
+        StmtKind::Return(Some(Expr { kind: ExprKind::Call { name: callee, args }, .. }))
+            if callee == name && args.len() == arity =>
+        {
+            Some(args)
+        }
+        _ => None,
+    }
+}
+
+fn ends_in_tail_call(stmts: &[Stmt], name: &str, arity: usize) -> bool {
+    match stmts.last() {
+        Some(last) if tail_call_args(last, name, arity).is_some() => true,
+        Some(Stmt { kind: StmtKind::IfStmt { then_branch, else_branch, .. }, .. }) => {
+            ends_in_tail_call(then_branch, name, arity)
+                || else_branch.as_deref().is_some_and(|b| ends_in_tail_call(b, name, arity))
+        }
+        _ => false,
+    }
+}
+
+/// Rewrite the tail position of `stmts` (a loop body): tail calls become
+/// parameter updates that fall through to the next iteration, and every other
+/// path ends in a `return`.
+fn rewrite_tail(mut stmts: Vec<Stmt>, tail: &TailContext, span: Span) -> Vec<Stmt> {
+    let params = tail.params;
+    let Some(last) = stmts.pop() else {
+        return vec![tail.fallthrough_return(span)];
+    };
+    if let Some(args) = tail_call_args(&last, tail.name, params.len()) {
+        // evaluate every argument before assigning any parameter
+        let temps: Vec<String> = (0..args.len()).map(|i| format!("__tail_{}", i)).collect();
+        for ((temp, arg), (_, ty)) in temps.iter().zip(args).zip(params) {
+            stmts.push(Stmt {
+                kind: StmtKind::VarDecl { name: temp.clone(), var_type: Some(*ty), value: arg.clone() },
+                span: last.span,
+            });
+        }
+        for ((param, _), temp) in params.iter().zip(&temps) {
+            let value = Expr::new(ExprKind::Identifier(temp.clone()), last.span);
+            stmts.push(Stmt { kind: StmtKind::Assignment { name: param.clone(), value }, span: last.span });
+        }
+        return stmts;
+    }
+    match last.kind {
+        StmtKind::IfStmt { condition, then_branch, else_branch } => {
+            let then_branch = rewrite_tail(then_branch, tail, last.span);
+            let else_branch = rewrite_tail(else_branch.unwrap_or_default(), tail, last.span);
+            stmts.push(Stmt {
+                kind: StmtKind::IfStmt { condition, then_branch, else_branch: Some(else_branch) },
+                span: last.span,
+            });
+        }
+        StmtKind::Return(_) => stmts.push(last),
+        _ => {
+            stmts.push(last);
+            stmts.push(tail.fallthrough_return(span));
+        }
+    }
+    stmts
+}
+
+/// The function whose tail calls `rewrite_tail` is rewriting.
+struct TailContext<'a> {
+    name: &'a str,
+    params: &'a [(String, Type)],
+    returns_unit: bool,
+}
+
+impl TailContext<'_> {
+    /// The `return` ending a path that used to fall off the end of the body.
+    fn fallthrough_return(&self, span: Span) -> Stmt {
+        let value = (!self.returns_unit).then_some(Expr::new(ExprKind::Number(Literal::Int(0)), span));
+        Stmt { kind: StmtKind::Return(value), span }
+    }
+}
+
+/// Run LLVM passes over `module`, chosen by `config.level`: none at `None`;
+/// instcombine and CFG simplification at `Less`; also reassociation, GVN and
+/// dead store elimination at `Default`; and at `Aggressive`, promotion of
+/// locals to registers first, so the rest see values instead of loads and
+/// stores. Size-based inlining runs at `Default` and above.
+pub fn run_llvm_optimizations(module: &Module, config: &OptConfig) {
+    // Module pass manager: inline small callees into their callers first so
+    // the function passes below can clean up the inlined bodies
+    let inlining_enabled = matches!(config.level, OptimizationLevel::Default | OptimizationLevel::Aggressive);
+    if let (true, Some(max_instrs)) = (inlining_enabled, config.inline_threshold) {
+        // at OptimizationLevel::None the builder populates only the inliner
+        let builder = PassManagerBuilder::create();
+        builder.set_optimization_level(OptimizationLevel::None);
+        builder.set_inliner_with_threshold(max_instrs * INLINE_COST_PER_INSTR);
+        let mpm = PassManager::create(());
+        builder.populate_module_pass_manager(&mpm);
+        mpm.run_on(module);
+    }
 
-pub fn run_llvm_optimizations(module: &Module) {
     // Function pass manager
     let fpm = PassManager::create(module);
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
-    fpm.add_gvn_pass();
-    fpm.add_cfg_simplification_pass();
-    fpm.add_dead_store_elimination_pass();
+    match config.level {
+        OptimizationLevel::None => return,
+        OptimizationLevel::Less => {
+            fpm.add_instruction_combining_pass();
+            fpm.add_cfg_simplification_pass();
+        }
+        level => {
+            if level == OptimizationLevel::Aggressive {
+                fpm.add_promote_memory_to_register_pass();
+            }
+            fpm.add_instruction_combining_pass();
+            fpm.add_reassociate_pass();
+            fpm.add_gvn_pass();
+            fpm.add_cfg_simplification_pass();
+            fpm.add_dead_store_elimination_pass();
+        }
+    }
     fpm.initialize();
 
     for func in module.get_functions() {
-        fpm.run_on(&func);
+        for _ in 0..config.max_pass_rounds {
+            if !fpm.run_on(&func) {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn parse(src: &str) -> Program {
+        Parser::new(Lexer::new(src.to_string()).tokenize().unwrap()).parse().unwrap()
     }
 
-    // Optionally you could also use a ModulePassManager (not shown here)
+    fn loop_body(program: &Program) -> &[Stmt] {
+        let StmtKind::Function { body, .. } = &program.statements[0].kind else { panic!("not a function") };
+        let StmtKind::While { body, .. } = &body[0].kind else { panic!("not rewritten into a loop") };
+        body
+    }
+
+    /// IR of `main` after compiling `src` and running the passes of `config`.
+    fn optimised_main_ir(src: &str, config: &OptConfig) -> String {
+        use crate::codegen_llvm::LLVMCodegen;
+        use inkwell::context::Context;
+        use inkwell::values::AnyValue;
+        let context = Context::create();
+        let mut codegen = LLVMCodegen::new(&context, "test");
+        codegen.compile_program(&parse(src)).unwrap();
+        run_llvm_optimizations(&codegen.module, config);
+        codegen.module.get_function("main").unwrap().print_to_string().to_string()
+    }
+
+    #[test]
+    fn only_small_functions_are_inlined() {
+        let big_body = "x = x * 3 + a; ".repeat(40);
+        let src = format!(
+            "fn small(a: i32) -> i32 {{ return a + 1; }}
+             fn big(a: i32) -> i32 {{ let x = a; {} return x; }}
+             fn main(n: i32) -> i32 {{ return small(n) + big(n); }}",
+            big_body
+        );
+        let ir = optimised_main_ir(&src, &OptConfig::default());
+        assert!(!ir.contains("call i32 @small"), "{}", ir);
+        assert!(ir.contains("call i32 @big"), "{}", ir);
+        let no_inlining = OptConfig { inline_threshold: None, ..OptConfig::default() };
+        assert!(optimised_main_ir(&src, &no_inlining).contains("call i32 @small"));
+    }
+
+    #[test]
+    fn unit_functions_fall_through_with_a_bare_return() {
+        let mut program = parse("fn count(n: i32) -> () { if n > 0 { print(n); return count(n - 1); } }");
+        rewrite_tail_recursion(&mut program);
+        let StmtKind::IfStmt { else_branch, .. } = &loop_body(&program)[0].kind else { panic!("expected the tail if") };
+        assert!(matches!(else_branch.as_deref(), Some([Stmt { kind: StmtKind::Return(None), .. }])));
+
+        let mut program = parse("fn down(n: i32) -> i32 { if n > 0 { return down(n - 1); } else { n = 0; } }");
+        rewrite_tail_recursion(&mut program);
+        let StmtKind::IfStmt { else_branch, .. } = &loop_body(&program)[0].kind else { panic!("expected the tail if") };
+        let else_branch = else_branch.as_deref().unwrap();
+        assert!(matches!(else_branch.last().unwrap().kind, StmtKind::Return(Some(_))));
+    }
+
+    #[test]
+    fn tail_recursive_sum_becomes_a_loop_with_the_same_result() {
+        use crate::codegen_bytecode::{VM, Value, compile_program};
+        let src = "fn sum(n: i32, acc: i32) -> i32 { if n > 0 { return sum(n - 1, acc + n); } return acc; }
+                   let total = sum(100, 0);";
+        let run = |program: &Program| {
+            let mut vm = VM::new(compile_program(program).unwrap());
+            vm.run().unwrap();
+            vm.vars["total"]
+        };
+        let recursive = parse(src);
+        let mut rewritten = recursive.clone();
+        rewrite_tail_recursion(&mut rewritten);
+        assert!(!loop_body(&rewritten).is_empty());
+        assert_eq!(run(&recursive), Value::Int(5050));
+        assert_eq!(run(&rewritten), Value::Int(5050));
+    }
+
+    #[test]
+    fn repeated_rounds_reach_a_fixpoint() {
+        use crate::codegen_llvm::LLVMCodegen;
+        use inkwell::context::Context;
+        // folding `r - x` needs the branches merged first, which needs a second round
+        let program = parse("fn f(x: i32) -> i32 { let r = 0; if x > 0 { r = x + 1; } else { r = 1 + x; } return r - x; }");
+        let entry_size = |rounds: u32| {
+            let context = Context::create();
+            let mut codegen = LLVMCodegen::new(&context, "test");
+            codegen.compile_program(&program).unwrap();
+            run_llvm_optimizations(&codegen.module, &OptConfig { max_pass_rounds: rounds, ..OptConfig::default() });
+            let f = codegen.module.get_function("f").unwrap();
+            f.get_first_basic_block().unwrap().get_instructions().count()
+        };
+        assert_eq!(entry_size(1), 3);
+        assert_eq!(entry_size(5), 1);
+    }
+
+    #[test]
+    fn constant_folding_leaves_runtime_operands_alone() {
+        let mut program = parse("let y = 1; let x = -(2 * 3) + y * (10 % 4); let z = 1 / 0;");
+        fold_constants(&mut program);
+        assert_eq!(program.to_source(), "let y = 1;\nlet x = -6 + y * 2;\nlet z = 1 / 0;\n");
+    }
+
+    #[test]
+    fn optimisation_level_changes_the_ir() {
+        let src = "fn main() -> i32 { let x = 2; let y = x * 3; return y + 1; }";
+        let at = |level| optimised_main_ir(src, &OptConfig { level, inline_threshold: None, ..OptConfig::default() });
+        let none = at(OptimizationLevel::None);
+        let aggressive = at(OptimizationLevel::Aggressive);
+        assert!(none.contains("alloca") && none.contains("mul"), "{}", none);
+        assert!(aggressive.contains("ret i32 7") && !aggressive.contains("alloca"), "{}", aggressive);
+    }
 }
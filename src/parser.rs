@@ -1,18 +1,44 @@
+use std::fmt;
+
 use crate::ast::*;
 use crate::lexer::{Token, TokenKind};
 
+/// A syntax error, positioned at the token where parsing failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl ParseError {
+    fn at(tok: &Token, message: impl Into<String>) -> Self {
+        ParseError { message: message.into(), line: tok.line, col: tok.col }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.message)
+    }
+}
+
 pub struct Parser {
     tokens: Vec<Token>,
     pos: usize,
+    /// errors from statements that were skipped by `synchronize`
+    errors: Vec<ParseError>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, pos: 0 }
+        Self { tokens, pos: 0, errors: Vec::new() }
     }
 
+    /// The next token, or `None` at the end of input. The lexer's trailing
+    /// `EOF` token counts as the end, so it is never parsed as a statement.
     fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.pos)
+        self.tokens.get(self.pos).filter(|t| t.kind != TokenKind::EOF)
     }
 
     fn next(&mut self) -> Option<Token> {
@@ -21,169 +47,775 @@ impl Parser {
         tok
     }
 
-    fn expect(&mut self, kind: TokenKind) -> Token {
-        let tok = self.next().expect("Unexpected end of input");
+    fn check(&self, kind: &TokenKind) -> bool {
+        self.peek().map(|t| &t.kind) == Some(kind)
+    }
+
+    /// Consume the next token, failing at the last token if input ran out.
+    fn advance(&mut self) -> Result<Token, ParseError> {
+        match self.next() {
+            Some(tok) => Ok(tok),
+            None => {
+                let last = self.tokens.last().cloned().unwrap_or(Token::new(TokenKind::EOF, String::new(), 1, 1));
+                Err(ParseError::at(&last, "Unexpected end of input"))
+            }
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Token, ParseError> {
+        let tok = self.advance()?;
         if tok.kind != kind {
-            panic!("Expected {:?}, got {:?}", kind, tok.kind);
+            return Err(ParseError::at(&tok, format!("Expected {:?}, got {:?}", kind, tok.kind)));
+        }
+        Ok(tok)
+    }
+
+    /// After an item of a parenthesised list: consume the `,` before the next
+    /// item, or stop at the closing `)`. A trailing `,` is allowed.
+    fn expect_list_separator(&mut self) -> Result<(), ParseError> {
+        if self.check(&TokenKind::RParen) {
+            return Ok(());
+        }
+        let tok = self.advance()?;
+        if tok.kind != TokenKind::Comma {
+            return Err(ParseError::at(&tok, format!("Expected `,` or `)`, got {:?}", tok.kind)));
+        }
+        Ok(())
+    }
+
+    /// Span from `start` up to the end of the last consumed token.
+    fn span_from(&self, start: Span) -> Span {
+        match self.pos.checked_sub(1).and_then(|i| self.tokens.get(i)) {
+            Some(tok) => start.to(tok.span()),
+            None => start,
         }
-        tok
     }
 
-    pub fn parse(&mut self) -> Vec<Stmt> {
+    /// Parse every statement in the input, failing with all syntax errors found.
+    pub fn parse(&mut self) -> Result<Program, Vec<ParseError>> {
+        let (program, errors) = self.parse_recovering();
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Like `parse`, but also returns the statements that did parse. A statement
+    /// that fails is recorded and skipped, so one run reports every error.
+    pub fn parse_recovering(&mut self) -> (Program, Vec<ParseError>) {
         let mut stmts = Vec::new();
         while self.peek().is_some() {
-            stmts.push(self.parse_stmt());
+            match self.parse_stmts() {
+                Ok(parsed) => stmts.extend(parsed),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(false);
+                }
+            }
+        }
+        (Program { statements: stmts }, std::mem::take(&mut self.errors))
+    }
+
+    /// Skip to the next statement boundary after an error: just past a `;`, or
+    /// up to a `}`. Inside a block the `}` is left for `parse_block` to close
+    /// the block with; at top level it is skipped as well.
+    fn synchronize(&mut self, in_block: bool) {
+        // The token the error was reported at may itself be the boundary.
+        self.pos = self.pos.min(self.tokens.len());
+        if let Some(prev) = self.pos.checked_sub(1).and_then(|i| self.tokens.get(i)) {
+            match prev.kind {
+                TokenKind::Semicolon => return,
+                TokenKind::RBrace => self.pos -= 1,
+                _ => {}
+            }
+        }
+        while let Some(tok) = self.peek() {
+            match tok.kind {
+                TokenKind::Semicolon => {
+                    self.next();
+                    return;
+                }
+                TokenKind::RBrace => {
+                    if !in_block {
+                        self.next();
+                    }
+                    return;
+                }
+                _ => {
+                    self.next();
+                }
+            }
         }
-        stmts
     }
 
-    fn parse_stmt(&mut self) -> Stmt {
+    /// One statement, or the statements a `for` loop is lowered to.
+    fn parse_stmts(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        if self.check(&TokenKind::For) {
+            return self.parse_for();
+        }
+        Ok(vec![self.parse_stmt()?])
+    }
+
+    fn parse_stmt(&mut self) -> Result<Stmt, ParseError> {
         match self.peek().map(|t| &t.kind) {
             Some(TokenKind::Let) => self.parse_let(),
             Some(TokenKind::If) => self.parse_if(),
             Some(TokenKind::While) => self.parse_while(),
+            Some(TokenKind::Do) => self.parse_do_while(),
             Some(TokenKind::Fn) => self.parse_function(),
             Some(TokenKind::Return) => self.parse_return(),
+            Some(TokenKind::Break) => self.parse_loop_jump(TokenKind::Break, StmtKind::Break),
+            Some(TokenKind::Continue) => self.parse_loop_jump(TokenKind::Continue, StmtKind::Continue),
+            Some(TokenKind::Ident) if self.tokens.get(self.pos + 1).map(|t| &t.kind) == Some(&TokenKind::Eq) => {
+                self.parse_assignment()
+            }
             _ => self.parse_expr_stmt(),
         }
     }
 
-    fn parse_let(&mut self) -> Stmt {
-        self.expect(TokenKind::Let);
-        let name = self.expect(TokenKind::Ident).value;
-        self.expect(TokenKind::Eq);
-        let expr = self.parse_expr();
-        self.expect(TokenKind::Semicolon);
-        Stmt::Let { name, expr }
+    fn parse_let(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.expect(TokenKind::Let)?.span();
+        if self.check(&TokenKind::LParen) {
+            return self.parse_let_tuple(start);
+        }
+        let name = self.expect(TokenKind::Ident)?.value;
+        // the annotation is checked against the known types by the semantic pass
+        let var_type = self.parse_type_annotation()?;
+        self.expect(TokenKind::Eq)?;
+        let value = self.parse_expr()?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Stmt {
+            kind: StmtKind::VarDecl { name, var_type, value },
+            span: self.span_from(start),
+        })
+    }
+
+    /// `name = value;`
+    fn parse_assignment(&mut self) -> Result<Stmt, ParseError> {
+        let target = self.expect(TokenKind::Ident)?;
+        self.expect(TokenKind::Eq)?;
+        let value = self.parse_expr()?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Stmt {
+            kind: StmtKind::Assignment { name: target.value.clone(), value },
+            span: self.span_from(target.span()),
+        })
+    }
+
+    /// An optional `: type` after a name.
+    fn parse_type_annotation(&mut self) -> Result<Option<Type>, ParseError> {
+        if !self.check(&TokenKind::Colon) {
+            return Ok(None);
+        }
+        self.next();
+        Ok(Some(self.parse_type()?))
+    }
+
+    /// A type name such as `i64`, or `()`.
+    fn parse_type(&mut self) -> Result<Type, ParseError> {
+        let tok = self.advance()?;
+        match tok.kind {
+            TokenKind::Ident => tok.value.parse().map_err(|msg| ParseError::at(&tok, msg)),
+            TokenKind::LParen => {
+                self.expect(TokenKind::RParen)?;
+                Ok(Type::Unit)
+            }
+            _ => Err(ParseError::at(&tok, format!("Expected a type, got {:?}", tok.kind))),
+        }
     }
 
-    fn parse_if(&mut self) -> Stmt {
-        self.expect(TokenKind::If);
-        let cond = self.parse_expr();
-        let then_block = self.parse_block();
-        let else_block = if self.peek().map(|t| t.kind.clone()) == Some(TokenKind::Else) {
+    /// `let (a, b) = value;`, after the `let`.
+    fn parse_let_tuple(&mut self, start: Span) -> Result<Stmt, ParseError> {
+        self.expect(TokenKind::LParen)?;
+        let mut names = vec![self.expect(TokenKind::Ident)?.value];
+        while self.check(&TokenKind::Comma) {
             self.next();
-            Some(self.parse_block())
+            let name = self.expect(TokenKind::Ident)?;
+            if names.contains(&name.value) {
+                return Err(ParseError::at(&name, format!("`{}` is bound more than once in this pattern", name.value)));
+            }
+            names.push(name.value);
+        }
+        self.expect(TokenKind::RParen)?;
+        self.expect(TokenKind::Eq)?;
+        let value = self.parse_expr()?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Stmt {
+            kind: StmtKind::VarDeclTuple { names, value },
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_if(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.expect(TokenKind::If)?.span();
+        let condition = self.parse_expr()?;
+        let then_branch = self.parse_block()?;
+        let else_branch = if self.peek().map(|t| t.kind.clone()) == Some(TokenKind::Else) {
+            self.next();
+            // `else if` nests the chained `if` as the sole statement of the else branch
+            if self.peek().map(|t| t.kind.clone()) == Some(TokenKind::If) {
+                Some(vec![self.parse_if()?])
+            } else {
+                Some(self.parse_block()?)
+            }
         } else {
             None
         };
-        Stmt::If { cond, then_block, else_block }
+        Ok(Stmt {
+            kind: StmtKind::IfStmt { condition, then_branch, else_branch },
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_while(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.expect(TokenKind::While)?.span();
+        let condition = self.parse_expr()?;
+        let body = self.parse_block()?;
+        Ok(Stmt {
+            kind: StmtKind::While { condition, body },
+            span: self.span_from(start),
+        })
+    }
+
+    /// `for (init; cond; step) { body }`, lowered to the init statement followed
+    /// by `while cond { body step; }`, so later passes never see a `for`. Each
+    /// section may be empty; a missing condition loops forever. A `let` in the
+    /// init section stays in scope after the loop.
+    fn parse_for(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let start = self.expect(TokenKind::For)?.span();
+        self.expect(TokenKind::LParen)?;
+        let init = if self.check(&TokenKind::Semicolon) {
+            self.next();
+            None
+        } else if self.check(&TokenKind::Let) {
+            Some(self.parse_let()?)
+        } else {
+            let init = self.parse_for_clause()?;
+            self.expect(TokenKind::Semicolon)?;
+            Some(init)
+        };
+        let condition = if self.check(&TokenKind::Semicolon) {
+            let span = self.peek().map(|t| t.span()).unwrap_or(start);
+            Expr::new(ExprKind::Number(Literal::Int(1)), span)
+        } else {
+            self.parse_expr()?
+        };
+        self.expect(TokenKind::Semicolon)?;
+        let step = if self.check(&TokenKind::RParen) { None } else { Some(self.parse_for_clause()?) };
+        self.expect(TokenKind::RParen)?;
+        let mut body = self.parse_block()?;
+        if let Some(step) = step {
+            insert_before_continue(&mut body, &step);
+            body.push(step);
+        }
+        let lowered = Stmt {
+            kind: StmtKind::While { condition, body },
+            span: self.span_from(start),
+        };
+        Ok(init.into_iter().chain([lowered]).collect())
     }
 
-    fn parse_while(&mut self) -> Stmt {
-        self.expect(TokenKind::While);
-        let cond = self.parse_expr();
-        let body = self.parse_block();
-        Stmt::While { cond, body }
+    /// The init or step section of a `for`: `name = value` or an expression,
+    /// without the trailing `;`.
+    fn parse_for_clause(&mut self) -> Result<Stmt, ParseError> {
+        if self.check(&TokenKind::Ident) && self.tokens.get(self.pos + 1).map(|t| &t.kind) == Some(&TokenKind::Eq) {
+            let target = self.expect(TokenKind::Ident)?;
+            self.expect(TokenKind::Eq)?;
+            let value = self.parse_expr()?;
+            return Ok(Stmt {
+                kind: StmtKind::Assignment { name: target.value.clone(), value },
+                span: self.span_from(target.span()),
+            });
+        }
+        let expr = self.parse_expr()?;
+        let span = expr.span;
+        Ok(Stmt { kind: StmtKind::ExprStmt(expr), span })
+    }
+
+    fn parse_do_while(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.expect(TokenKind::Do)?.span();
+        let body = self.parse_block()?;
+        self.expect(TokenKind::While)?;
+        let condition = self.parse_expr()?;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Stmt {
+            kind: StmtKind::DoWhile { body, condition },
+            span: self.span_from(start),
+        })
     }
 
-    fn parse_function(&mut self) -> Stmt {
-        self.expect(TokenKind::Fn);
-        let name = self.expect(TokenKind::Ident).value;
-        self.expect(TokenKind::LParen);
+    fn parse_function(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.expect(TokenKind::Fn)?.span();
+        let name = self.expect(TokenKind::Ident)?.value;
+        self.expect(TokenKind::LParen)?;
         let mut params = Vec::new();
         while let Some(tok) = self.peek() {
             if tok.kind == TokenKind::RParen {
                 break;
             }
-            let param_name = self.expect(TokenKind::Ident).value;
-            params.push(param_name);
-            if let Some(tok) = self.peek() {
-                if tok.kind == TokenKind::Comma {
-                    self.next();
-                }
+            let param = self.expect(TokenKind::Ident)?;
+            if params.iter().any(|(p, _)| *p == param.value) {
+                return Err(ParseError::at(
+                    &param,
+                    format!("duplicate parameter `{}` in function `{}`", param.value, name),
+                ));
             }
+            params.push((param.value, self.parse_type_annotation()?.unwrap_or(Type::I32)));
+            self.expect_list_separator()?;
         }
-        self.expect(TokenKind::RParen);
-        let body = self.parse_block();
-        Stmt::Function { name, params, body }
+        self.expect(TokenKind::RParen)?;
+        let ret_type = if self.check(&TokenKind::Arrow) {
+            self.next();
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        let body = self.parse_block()?;
+        Ok(Stmt {
+            kind: StmtKind::Function { name, params, ret_type, body },
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_return(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.expect(TokenKind::Return)?.span();
+        let expr = if self.check(&TokenKind::Semicolon) {
+            None
+        } else {
+            Some(self.parse_expr()?)
+        };
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Stmt {
+            kind: StmtKind::Return(expr),
+            span: self.span_from(start),
+        })
     }
 
-    fn parse_return(&mut self) -> Stmt {
-        self.expect(TokenKind::Return);
-        let expr = self.parse_expr();
-        self.expect(TokenKind::Semicolon);
-        Stmt::Return(expr)
+    /// `break;` or `continue;`
+    fn parse_loop_jump(&mut self, keyword: TokenKind, kind: StmtKind) -> Result<Stmt, ParseError> {
+        let start = self.expect(keyword)?.span();
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Stmt { kind, span: self.span_from(start) })
     }
 
-    fn parse_expr_stmt(&mut self) -> Stmt {
-        let expr = self.parse_expr();
-        self.expect(TokenKind::Semicolon);
-        Stmt::Expr(expr)
+    fn parse_expr_stmt(&mut self) -> Result<Stmt, ParseError> {
+        let expr = self.parse_expr()?;
+        let start = expr.span;
+        self.expect(TokenKind::Semicolon)?;
+        Ok(Stmt {
+            kind: StmtKind::ExprStmt(expr),
+            span: self.span_from(start),
+        })
     }
 
-    fn parse_block(&mut self) -> Vec<Stmt> {
-        self.expect(TokenKind::LBrace);
+    fn parse_block(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        self.expect(TokenKind::LBrace)?;
         let mut stmts = Vec::new();
         while let Some(tok) = self.peek() {
             if tok.kind == TokenKind::RBrace {
                 break;
             }
-            stmts.push(self.parse_stmt());
+            match self.parse_stmts() {
+                Ok(parsed) => stmts.extend(parsed),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize(true);
+                }
+            }
         }
-        self.expect(TokenKind::RBrace);
-        stmts
+        self.expect(TokenKind::RBrace)?;
+        Ok(stmts)
     }
 
-    fn parse_expr(&mut self) -> Expr {
-        self.parse_binary()
+    fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(0)
     }
 
-    fn parse_binary(&mut self) -> Expr {
-        let mut left = self.parse_primary();
+    /// Precedence climbing: parse operands joined by operators that bind at
+    /// least as tightly as `min_prec`. Operators of equal precedence associate
+    /// to the left.
+    fn parse_binary(&mut self, min_prec: u8) -> Result<Expr, ParseError> {
+        let mut left = self.parse_unary()?;
         while let Some(tok) = self.peek() {
-            match tok.kind {
-                TokenKind::Plus | TokenKind::Minus | TokenKind::Star | TokenKind::Slash => {
-                    let op = tok.kind.clone();
-                    self.next();
-                    let right = self.parse_primary();
-                    left = Expr::Binary {
-                        op: op.to_string(),
-                        left: Box::new(left),
-                        right: Box::new(right),
-                    };
-                }
+            let Some(prec) = tok.kind.precedence().filter(|p| *p >= min_prec) else {
+                break;
+            };
+            let operator = match tok.kind {
+                TokenKind::Plus => BinOp::Add,
+                TokenKind::Minus => BinOp::Sub,
+                TokenKind::Star => BinOp::Mul,
+                TokenKind::Slash => BinOp::Div,
+                TokenKind::Percent => BinOp::Mod,
+                TokenKind::Gt => BinOp::Gt,
+                TokenKind::Lt => BinOp::Lt,
+                TokenKind::GtEq => BinOp::Ge,
+                TokenKind::LtEq => BinOp::Le,
+                TokenKind::EqEq => BinOp::Eq,
+                TokenKind::NotEq => BinOp::Neq,
+                TokenKind::AndAnd => BinOp::And,
+                TokenKind::OrOr => BinOp::Or,
                 _ => break,
-            }
+            };
+            self.next();
+            let right = self.parse_binary(prec + 1)?;
+            let span = left.span.to(right.span);
+            left = Expr::new(
+                ExprKind::Binary {
+                    left: Box::new(left),
+                    operator,
+                    right: Box::new(right),
+                },
+                span,
+            );
         }
-        left
+        Ok(left)
     }
 
-    fn parse_primary(&mut self) -> Expr {
-        let tok = self.next().expect("Unexpected end of input");
-        match tok.kind {
-            TokenKind::Number => Expr::Number(tok.value.parse().unwrap()),
+    /// Prefix `-` and `!`, which bind tighter than any binary operator.
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        let op = match self.peek().map(|t| &t.kind) {
+            Some(TokenKind::Minus) => UnaryOp::Neg,
+            Some(TokenKind::Bang) => UnaryOp::Not,
+            _ => return self.parse_primary(),
+        };
+        let start = self.advance()?.span();
+        let operand = self.parse_unary()?;
+        let span = start.to(operand.span);
+        // `-5` is the literal -5, so `-2147483648` still fits an i32
+        if let (UnaryOp::Neg, ExprKind::Number(Literal::Int(n))) = (op, &operand.kind) {
+            return Ok(Expr::new(ExprKind::Number(Literal::Int(-n)), span));
+        }
+        Ok(Expr::new(ExprKind::Unary { op, operand: Box::new(operand) }, span))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let tok = self.advance()?;
+        let start = tok.span();
+        let expr = match tok.kind {
+            TokenKind::Number => {
+                // range checks against the literal's eventual type happen in the semantic pass
+                let lit = if tok.value.contains('.') {
+                    tok.value.parse().map(Literal::Float).ok()
+                } else {
+                    tok.value.parse().map(Literal::Int).ok()
+                };
+                let lit =
+                    lit.ok_or_else(|| ParseError::at(&tok, format!("numeric literal {} is too large", tok.value)))?;
+                Expr::new(ExprKind::Number(lit), start)
+            }
+            TokenKind::Bool => Expr::new(ExprKind::Number(Literal::Bool(tok.value == "true")), start),
             TokenKind::Ident => {
-                if let Some(next) = self.peek() {
-                    if next.kind == TokenKind::LParen {
-                        self.next();
-                        let mut args = Vec::new();
-                        while let Some(arg) = self.peek() {
-                            if arg.kind == TokenKind::RParen {
-                                break;
-                            }
-                            args.push(self.parse_expr());
-                            if let Some(tok) = self.peek() {
-                                if tok.kind == TokenKind::Comma {
-                                    self.next();
-                                }
-                            }
+                if self.check(&TokenKind::LParen) {
+                    self.next();
+                    let mut args = Vec::new();
+                    while let Some(arg) = self.peek() {
+                        if arg.kind == TokenKind::RParen {
+                            break;
                         }
-                        self.expect(TokenKind::RParen);
-                        Expr::Call { name: tok.value, args }
-                    } else {
-                        Expr::Var(tok.value)
+                        args.push(self.parse_expr()?);
+                        self.expect_list_separator()?;
                     }
+                    self.expect(TokenKind::RParen)?;
+                    Expr::new(ExprKind::Call { name: tok.value, args }, self.span_from(start))
                 } else {
-                    Expr::Var(tok.value)
+                    Expr::new(ExprKind::Identifier(tok.value), start)
                 }
             }
             TokenKind::LParen => {
-                let expr = self.parse_expr();
-                self.expect(TokenKind::RParen);
-                expr
+                let expr = self.parse_expr()?;
+                if self.check(&TokenKind::Comma) {
+                    let mut elems = vec![expr];
+                    while self.check(&TokenKind::Comma) {
+                        self.next();
+                        elems.push(self.parse_expr()?);
+                    }
+                    self.expect(TokenKind::RParen)?;
+                    Expr::new(ExprKind::Tuple(elems), self.span_from(start))
+                } else {
+                    self.expect(TokenKind::RParen)?;
+                    expr
+                }
+            }
+            _ => return Err(ParseError::at(&tok, format!("Unexpected token {:?}", tok.kind))),
+        };
+
+        let mut expr = expr;
+        while self.check(&TokenKind::Dot) {
+            self.next();
+            let name = self.expect(TokenKind::Ident)?.value;
+            expr = Expr::new(ExprKind::Field { base: Box::new(expr), name }, self.span_from(start));
+        }
+
+        // Only named functions can be called; `add(1, 2)(3)` or `(f)(x)` would
+        // need first-class functions.
+        if let Some(paren) = self.peek().filter(|t| t.kind == TokenKind::LParen) {
+            return Err(ParseError::at(
+                paren,
+                "cannot call the result of an expression; only named functions can be called",
+            ));
+        }
+        Ok(expr)
+    }
+}
+
+/// Run a lowered `for` loop's step before every `continue` that targets it,
+/// since the `continue` of the resulting `while` jumps straight to the
+/// condition. Nested loops own their `continue`s and are left alone.
+fn insert_before_continue(stmts: &mut Vec<Stmt>, step: &Stmt) {
+    let mut i = 0;
+    while i < stmts.len() {
+        match &mut stmts[i].kind {
+            StmtKind::Continue => {
+                stmts.insert(i, step.clone());
+                i += 1;
             }
-            _ => panic!("Unexpected token {:?}", tok.kind),
+            StmtKind::IfStmt { then_branch, else_branch, .. } => {
+                insert_before_continue(then_branch, step);
+                if let Some(else_branch) = else_branch {
+                    insert_before_continue(else_branch, step);
+                }
+            }
+            _ => {}
         }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(src: &str) -> Result<Program, Vec<ParseError>> {
+        Parser::new(Lexer::new(src.to_string()).tokenize().unwrap()).parse()
+    }
+
+    #[test]
+    fn calling_a_call_result_is_a_parse_error() {
+        let errs = parse("fn add(a: i32, b: i32) -> i32 { return a + b; } let x = add(1, 2)(3);").unwrap_err();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].message, "cannot call the result of an expression; only named functions can be called");
+        assert_eq!((errs[0].line, errs[0].col), (1, 66));
+    }
+
+    #[test]
+    fn list_items_need_commas() {
+        assert!(parse("fn f(a: i32, b: i32,) { } f(1, 2,); f();").is_ok());
+        let errs = parse("fn f(a: i32 b: i32) { }").unwrap_err();
+        assert_eq!((errs[0].line, errs[0].col), (1, 13));
+        assert!(errs[0].message.contains("Expected `,` or `)`"));
+        let errs = parse("add(1 2);").unwrap_err();
+        assert_eq!((errs[0].line, errs[0].col), (1, 7));
+    }
+
+    #[test]
+    fn unknown_type_name_is_a_parse_error() {
+        let errs = parse("let x: int = 1;").unwrap_err();
+        assert_eq!(errs[0].message, "unknown type `int`");
+        assert_eq!((errs[0].line, errs[0].col), (1, 8));
+        assert!(parse("fn f() -> foo { }").is_err());
+    }
+
+    #[test]
+    fn every_syntax_error_is_reported_and_the_rest_still_parses() {
+        let src = "let a = ;\nlet b = 2;\nfn f() { let c = 1 let d = 4; g(); }\nlet e = 3 }\nlet h = 5;";
+        let errs = parse(src).unwrap_err();
+        assert_eq!(errs.iter().map(|e| e.line).collect::<Vec<_>>(), vec![1, 3, 4]);
+
+        let tokens = Lexer::new("let b = 2; fn f() { let c = ; g(); } let h = (; let i = 1;".to_string()).tokenize().unwrap();
+        let (program, errs) = Parser::new(tokens).parse_recovering();
+        assert_eq!(errs.len(), 2);
+        assert_eq!(program.statements.len(), 3);
+        match &program.statements[1].kind {
+            StmtKind::Function { name, body, .. } => assert_eq!((name.as_str(), body.len()), ("f", 1)),
+            other => panic!("expected a function, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_parameters_are_rejected() {
+        let errs = parse("fn f(a: i32, a: i32) {}").unwrap_err();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].message, "duplicate parameter `a` in function `f`");
+        assert_eq!((errs[0].line, errs[0].col), (1, 14));
+    }
+
+    #[test]
+    fn radix_literals_parse_to_their_value() {
+        let program = parse("let a = 0xFF; let b = 0b1010;").unwrap();
+        let values: Vec<_> = program
+            .statements
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::VarDecl { value: Expr { kind: ExprKind::Number(lit), .. }, .. } => *lit,
+                other => panic!("expected a literal let, found {:?}", other),
+            })
+            .collect();
+        assert_eq!(values, vec![Literal::Int(255), Literal::Int(10)]);
+    }
+
+    #[test]
+    fn let_type_annotations_are_optional() {
+        let program = parse("let x: i32 = 5; let y = 3;").unwrap();
+        let types: Vec<_> = program
+            .statements
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::VarDecl { var_type, .. } => *var_type,
+                other => panic!("expected a let, found {:?}", other),
+            })
+            .collect();
+        assert_eq!(types, [Some(Type::I32), None]);
+        assert!(parse("let x: = 5;").is_err());
+    }
+
+    #[test]
+    fn function_signatures_keep_parameter_and_return_types() {
+        let program = parse("fn add(a: i32, b: i32) -> i32 { return a + b; } fn g(x) { return x - 1; }").unwrap();
+        let StmtKind::Function { params, ret_type, .. } = &program.statements[0].kind else { panic!("expected a function") };
+        assert_eq!(params, &[("a".to_string(), Type::I32), ("b".to_string(), Type::I32)]);
+        assert_eq!(*ret_type, Some(Type::I32));
+        let StmtKind::Function { params, ret_type, .. } = &program.statements[1].kind else { panic!("expected a function") };
+        assert_eq!((params[0].1, *ret_type), (Type::I32, None));
+    }
+
+    #[test]
+    fn trailing_eof_ends_the_program() {
+        assert_eq!(parse("let a = 1; fn f() { return a; }").unwrap().statements.len(), 2);
+        assert_eq!(parse("").unwrap().statements.len(), 0);
+        assert_eq!(parse("fn f() { return 1;").unwrap_err().len(), 1);
+        assert!(parse("let a = ").is_err());
+    }
+
+    /// The expression of the single statement `src;`, without spans.
+    fn expr_of(src: &str) -> Expr {
+        let program = crate::utils::without_spans(&parse(&format!("{};", src)).unwrap());
+        match &program.statements[0].kind {
+            StmtKind::ExprStmt(expr) => expr.clone(),
+            other => panic!("expected an expression statement, found {:?}", other),
+        }
+    }
+
+    fn binary(left: Expr, operator: BinOp, right: Expr) -> Expr {
+        Expr::new(ExprKind::Binary { left: Box::new(left), operator, right: Box::new(right) }, Span::default())
+    }
+
+    fn name(name: &str) -> Expr {
+        Expr::new(ExprKind::Identifier(name.to_string()), Span::default())
+    }
+
+    fn int(n: i128) -> Expr {
+        Expr::new(ExprKind::Number(Literal::Int(n)), Span::default())
+    }
+
+    #[test]
+    fn comparisons_bind_looser_than_arithmetic() {
+        assert_eq!(expr_of("z > 10"), binary(name("z"), BinOp::Gt, int(10)));
+        assert_eq!(expr_of("a == b"), binary(name("a"), BinOp::Eq, name("b")));
+        assert_eq!(
+            expr_of("x + 1 < y * 2"),
+            binary(binary(name("x"), BinOp::Add, int(1)), BinOp::Lt, binary(name("y"), BinOp::Mul, int(2)))
+        );
+    }
+
+    #[test]
+    fn program_holds_each_top_level_statement() {
+        let program = parse("fn add(a: i32, b: i32) -> i32 { return a + b; } let x = 1; x = add(x, 2); if x > 2 { x = 0; }").unwrap();
+        assert_eq!(program.statements.len(), 4);
+        assert!(matches!(program.statements[2].kind, StmtKind::Assignment { .. }));
+    }
+
+    #[test]
+    fn return_value_is_optional() {
+        let program = parse("fn f() { return; } fn g(a: i32, b: i32) -> i32 { return a + b; }").unwrap();
+        let first_stmt = |i: usize| match &program.statements[i].kind {
+            StmtKind::Function { body, .. } => body[0].kind.clone(),
+            other => panic!("expected a function, found {:?}", other),
+        };
+        assert_eq!(first_stmt(0), StmtKind::Return(None));
+        assert!(matches!(
+            first_stmt(1),
+            StmtKind::Return(Some(Expr { kind: ExprKind::Binary { operator: BinOp::Add, .. }, .. }))
+        ));
+    }
+
+    #[test]
+    fn malformed_input_returns_errors_instead_of_panicking() {
+        let errors = |src: &str| parse(src).unwrap_err().iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(errors("let a = 1\nlet b = 2;"), ["2:1: Expected Semicolon, got Let"]);
+        assert_eq!(errors("let a = 1;\nlet = 2;\n) + 1;"), ["2:5: Expected Ident, got Eq", "3:1: Unexpected token RParen"]);
+    }
+
+    #[test]
+    fn for_loops_desugar_to_while() {
+        let for_loop = parse("let s = 0; for (let i = 0; i < 5; i = i + 1) { s = s + i; }").unwrap();
+        let while_loop = parse("let s = 0; let i = 0; while i < 5 { s = s + i; i = i + 1; }").unwrap();
+        assert_eq!(crate::utils::ast_diff(&while_loop, &for_loop), None);
+        assert!(parse("for (let i = 0; i < 3) {}").is_err());
+    }
+
+    #[test]
+    fn member_access_nests_left_to_right() {
+        let program = parse("a.b.c;").unwrap();
+        let StmtKind::ExprStmt(expr) = &program.statements[0].kind else { panic!("expected an expression statement") };
+        let ExprKind::Field { base, name } = &expr.kind else { panic!("expected a field, found {:?}", expr.kind) };
+        assert_eq!(name, "c");
+        let ExprKind::Field { base, name } = &base.kind else { panic!("expected a field, found {:?}", base.kind) };
+        assert_eq!(name, "b");
+        assert_eq!(base.kind, ExprKind::Identifier("a".to_string()));
+        // a dot followed by digits is still a float
+        assert!(matches!(
+            parse("1.5;").unwrap().statements[0].kind,
+            StmtKind::ExprStmt(Expr { kind: ExprKind::Number(Literal::Float(_)), .. })
+        ));
+    }
+
+    #[test]
+    fn type_names_parse_to_the_type_enum() {
+        let program = parse("let x: i64 = 1; let y: f64 = 1.0; let z: bool = true; fn f() -> () { }").unwrap();
+        let types: Vec<_> = program
+            .statements
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::VarDecl { var_type, .. } => *var_type,
+                StmtKind::Function { ret_type, .. } => *ret_type,
+                other => panic!("unexpected statement {:?}", other),
+            })
+            .collect();
+        assert_eq!(types, [Some(Type::I64), Some(Type::F64), Some(Type::Bool), Some(Type::Unit)]);
+    }
+
+    #[test]
+    fn operator_tokens_become_operator_enums() {
+        let cases = [
+            ("a + b", BinOp::Add),
+            ("a - b", BinOp::Sub),
+            ("a * b", BinOp::Mul),
+            ("a / b", BinOp::Div),
+            ("a % b", BinOp::Mod),
+            ("a > b", BinOp::Gt),
+            ("a < b", BinOp::Lt),
+            ("a == b", BinOp::Eq),
+            ("a != b", BinOp::Neq),
+            ("a >= b", BinOp::Ge),
+            ("a <= b", BinOp::Le),
+            ("a && b", BinOp::And),
+            ("a || b", BinOp::Or),
+        ];
+        for (src, op) in cases {
+            assert_eq!(expr_of(src), binary(name("a"), op, name("b")), "{}", src);
+        }
+        assert!(matches!(expr_of("-a").kind, ExprKind::Unary { op: UnaryOp::Neg, .. }));
+        assert!(matches!(expr_of("!a").kind, ExprKind::Unary { op: UnaryOp::Not, .. }));
+    }
+
+    #[test]
+    fn binary_span_covers_both_operands() {
+        let program = parse("let x = alpha +\n  beta * 2;").unwrap();
+        let StmtKind::VarDecl { value, .. } = &program.statements[0].kind else { panic!("expected a let") };
+        assert_eq!(value.span, Span::new(1, 9, 2, 11));
+        let ExprKind::Binary { right, .. } = &value.kind else { panic!("expected a binary expression") };
+        assert_eq!(right.span, Span::new(2, 3, 2, 11));
     }
 }
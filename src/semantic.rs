@@ -1,88 +1,1509 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use crate::ast::*;
 
+/// The distinct problems the analyzer reports. Each has a stable code that is
+/// printed with the diagnostic and can be looked up with `--explain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    UndeclaredVariable,
+    TypeMismatch,
+    FunctionAsValue,
+    UndeclaredFunction,
+    LiteralOutOfRange,
+    OutsideLoop,
+    WrongArgumentCount,
+    MissingReturn,
+    ReturnOutsideFunction,
+    CannotInferType,
+    StatementOutsideFunction,
+    TruncatingDivision,
+    UnusedVariable,
+    UnusedFunction,
+}
+
+/// Codes that were reported by earlier versions, with what replaced them.
+const RETIRED_CODES: [(&str, &str); 1] = [(
+    "E006",
+    "Retired: an unknown type name in an annotation is now a syntax error.
+
+    let x: int = 1; // error: unknown type `int`
+
+E006 was reported by the semantic pass before type annotations were parsed
+into types. The parser now rejects the annotation, so E006 is never emitted.",
+)];
+
+impl DiagnosticKind {
+    pub const ALL: [DiagnosticKind; 14] = [
+        DiagnosticKind::UndeclaredVariable,
+        DiagnosticKind::TypeMismatch,
+        DiagnosticKind::FunctionAsValue,
+        DiagnosticKind::UndeclaredFunction,
+        DiagnosticKind::LiteralOutOfRange,
+        DiagnosticKind::OutsideLoop,
+        DiagnosticKind::WrongArgumentCount,
+        DiagnosticKind::MissingReturn,
+        DiagnosticKind::ReturnOutsideFunction,
+        DiagnosticKind::CannotInferType,
+        DiagnosticKind::StatementOutsideFunction,
+        DiagnosticKind::TruncatingDivision,
+        DiagnosticKind::UnusedVariable,
+        DiagnosticKind::UnusedFunction,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            DiagnosticKind::UndeclaredVariable => "E001",
+            DiagnosticKind::TypeMismatch => "E002",
+            DiagnosticKind::FunctionAsValue => "E003",
+            DiagnosticKind::UndeclaredFunction => "E004",
+            DiagnosticKind::LiteralOutOfRange => "E005",
+            DiagnosticKind::OutsideLoop => "E007",
+            DiagnosticKind::WrongArgumentCount => "E008",
+            DiagnosticKind::MissingReturn => "E009",
+            DiagnosticKind::ReturnOutsideFunction => "E010",
+            DiagnosticKind::CannotInferType => "E011",
+            DiagnosticKind::StatementOutsideFunction => "E012",
+            DiagnosticKind::TruncatingDivision => "W001",
+            DiagnosticKind::UnusedVariable => "W002",
+            DiagnosticKind::UnusedFunction => "W003",
+        }
+    }
+
+    /// Look up a kind by its code, ignoring case (`e001` works too).
+    pub fn from_code(code: &str) -> Option<DiagnosticKind> {
+        DiagnosticKind::ALL.into_iter().find(|k| k.code().eq_ignore_ascii_case(code))
+    }
+
+    /// Explanation for a code that is no longer reported, so `--explain` still
+    /// answers for it. Codes are never reused.
+    pub fn retired_explanation(code: &str) -> Option<&'static str> {
+        RETIRED_CODES.iter().find(|(c, _)| c.eq_ignore_ascii_case(code)).map(|&(_, text)| text)
+    }
+
+    /// Longer description with an example, printed by `--explain`.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            DiagnosticKind::UndeclaredVariable => {
+                "A variable was used or assigned before any `let` declared it.
+
+    let y = x + 1; // `x` has not been declared
+    let x = 2;
+
+Declare the variable with `let` before its first use. Variables declared
+inside a block are not visible after the block ends."
+            }
+            DiagnosticKind::TypeMismatch => {
+                "A value has the wrong type for where it is used.
+
+    let ok = 1 < 2;
+    let bad = 1 && 2; // `&&` needs two booleans
+
+Arithmetic (`+`, `-`, `*`, `/`, `%`) and ordering comparisons (`<`, `>`, `<=`,
+`>=`) take numbers, and `&&`/`||` take booleans. Apart from `&&`/`||`, both
+operands must have the same type: there are no implicit conversions, so an
+i32 cannot be added to an i64. Comparisons produce a boolean. Likewise `-x`
+needs a number and `!x` a boolean.
+
+The same holds wherever a value meets a declared type: a `let` initializer or
+an assignment and the variable, a `return` and the function's result, and a
+call argument and its parameter. The condition of an `if` or a loop must be a
+bool or an integer."
+            }
+            DiagnosticKind::FunctionAsValue => {
+                "A function name was used where a value is expected.
+
+    fn five() { return 5; }
+    let x = five; // should be `five()`
+
+Functions are not first-class values; call the function instead."
+            }
+            DiagnosticKind::UndeclaredFunction => {
+                "A function was called that is not defined anywhere it can be seen.
+
+    let x = duble(2); // typo for `double`
+    fn double(n) { return n * 2; }
+
+Top-level functions can be called from anywhere in the file, including above
+their definition. A function defined inside a block can only be called after
+its definition."
+            }
+            DiagnosticKind::LiteralOutOfRange => {
+                "A numeric literal does not fit the type it is used as.
+
+    let a: i32 = 3000000000; // larger than i32::MAX
+    let b: i64 = 3000000000; // fine
+    let c: i32 = 1.5;        // a float literal is not an integer
+
+An unannotated integer literal is an i32 (an i64 with `--default-int i64`),
+widening to i64 if it needs to. Annotate the variable with a type wide enough
+for the value."
+            }
+            DiagnosticKind::OutsideLoop => {
+                "`break` or `continue` was used outside of a loop.
+
+    if done {
+        break; // no enclosing `while`
+    }
+
+Both only make sense inside the body of a `while` or `do ... while` loop. A
+function body starts outside any loop, even when the function is defined
+inside one."
+            }
+            DiagnosticKind::WrongArgumentCount => {
+                "A function was called with the wrong number of arguments.
+
+    fn add(a: i32, b: i32) -> i32 { return a + b; }
+    let x = add(1); // `add` takes 2 arguments
+
+Pass exactly one argument for each parameter in the function's definition."
+            }
+            DiagnosticKind::MissingReturn => {
+                "A function with a declared result type can finish without returning.
+
+    fn sign(n: i32) -> i32 {
+        if n < 0 {
+            return -1;
+        } // nothing is returned when n >= 0
+    }
+
+Every path through the body must reach a `return` (an `if` counts only when
+both branches return). Functions declared `-> ()` are exempt, as are functions
+with no `-> type`, which return 0 when they fall off the end."
+            }
+            DiagnosticKind::ReturnOutsideFunction => {
+                "`return` was used outside of any function.
+
+    fn main() { return 0; }
+    return 1; // not inside `main`
+
+Top-level code normally forms the body of an implicit `main`, so a top-level
+`return` ends the program. A program that defines its own `fn main` has no
+implicit one, and there `return` is only allowed inside a function."
+            }
+            DiagnosticKind::CannotInferType => {
+                "The type of an unannotated `let` could not be worked out from its
+initializer.
+
+    fn log(n: i32) -> () { }
+    let x = log(1); // `log` produces no value
+
+A `let` without a type takes the type of its initializer: `let y = 3;` is an
+i32 and `let b = a < c;` a bool. This fails when the initializer has no
+value, as with a call to a function returning `()`."
+            }
+            DiagnosticKind::StatementOutsideFunction => {
+                "A program that defines `fn main` has a statement outside of any function.
+
+    let limit = 10; // not part of `main`
+    fn main() -> i32 { return 0; }
+
+Without a `fn main`, top-level statements form the body of an implicit one.
+Once the program defines its own `main`, that is where it starts, and top-level
+code would never run. Move the statement into `main` or another function.
+A top-level `return` is reported as E010 instead."
+            }
+            DiagnosticKind::TruncatingDivision => {
+                "Integer division discards the remainder (enabled by `--lint-int-div`).
+
+    let half = 7 / 2;      // 3, not 3.5
+    let h: f64 = 7 / 2;    // 3.5: the literals are f64 here
+
+If the remainder matters, compute it separately with `%` or scale the values
+first. The lint also flags a division of integer literals that is done in
+f64, whose result differs from the same division of integers."
+            }
+            DiagnosticKind::UnusedVariable => {
+                "A variable is declared but its value is never read.
+
+    let total = 0;
+    total = 5; // assigning does not count as a use
+
+The variable can usually be removed. If it is kept on purpose, start its name
+with `_` (`let _total = 0;`) to silence the warning."
+            }
+            DiagnosticKind::UnusedFunction => {
+                "A function is defined but never called.
+
+    fn helper() { return 1; } // nothing calls `helper`
+
+Remove the function, or call it. `main` is never reported, and neither is a
+function whose name starts with `_`."
+            }
+        }
+    }
+}
+
+/// A problem found during semantic analysis, pointing at the offending node.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: [{}] {}", self.span, self.kind.code(), self.message)
+    }
+}
+
+/// Type of an expression as far as the analyzer can tell. `Unknown` is used
+/// for names it has no information about, and is never reported as a mismatch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExprType {
+    I32,
+    I64,
+    F64,
+    Bool,
+    Unknown,
+}
+
+impl ExprType {
+    /// Type of the values of a written type; `None` for `()`, which has none.
+    pub fn from_type(ty: Type) -> Option<ExprType> {
+        match ty {
+            Type::I32 => Some(ExprType::I32),
+            Type::I64 => Some(ExprType::I64),
+            Type::F64 => Some(ExprType::F64),
+            Type::Bool => Some(ExprType::Bool),
+            Type::Unit => None,
+        }
+    }
+
+    /// The written type for this type, if it is known.
+    pub fn to_type(self) -> Option<Type> {
+        match self {
+            ExprType::I32 => Some(Type::I32),
+            ExprType::I64 => Some(Type::I64),
+            ExprType::F64 => Some(Type::F64),
+            ExprType::Bool => Some(Type::Bool),
+            ExprType::Unknown => None,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, ExprType::I32 | ExprType::I64 | ExprType::F64)
+    }
+}
+
+impl fmt::Display for ExprType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExprType::I32 => write!(f, "i32"),
+            ExprType::I64 => write!(f, "i64"),
+            ExprType::F64 => write!(f, "f64"),
+            ExprType::Bool => write!(f, "bool"),
+            ExprType::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Function,
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolKind::Variable => write!(f, "variable"),
+            SymbolKind::Parameter => write!(f, "parameter"),
+            SymbolKind::Function => write!(f, "function"),
+        }
+    }
+}
+
+/// A name declared somewhere in the program. `depth` counts the blocks
+/// (function bodies, `if` branches, loop bodies) around the declaration, so
+/// top-level names are at depth 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// `i32`, `bool`, ... for values; `fn(i32, i32) -> i32` for functions
+    pub ty: String,
+    pub depth: usize,
+    pub span: Span,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} (scope depth {})", self.kind, self.name, self.ty, self.depth)
+    }
+}
+
+/// A variable in scope.
+struct Binding {
+    ty: ExprType,
+    /// index of its declaration in `SemanticAnalyzer::symbols`
+    symbol: usize,
+    /// whether it is read anywhere
+    used: bool,
+}
+
+/// Name of the builtin that prints an i32 followed by a newline. It is known
+/// to the analyzer without a definition, and the backends implement it. A
+/// program may define its own `print`, which then replaces the builtin.
+pub const PRINT_BUILTIN: &str = "print";
+
 pub struct SemanticAnalyzer {
-    variables: HashSet<String>,
-    functions: HashSet<String>,
+    /// variables in scope, innermost block last; the first scope holds the
+    /// top-level `let`s
+    scopes: Vec<HashMap<String, Binding>>,
+    /// parameter and result types of each function declared so far
+    functions: HashMap<String, (Vec<ExprType>, ExprType)>,
+    /// functions called anywhere in the program
+    called: HashSet<String>,
+    pub errors: Vec<Diagnostic>,
+    pub warnings: Vec<Diagnostic>,
+    /// Opt-in lint: warn when a constant integer division truncates (`7 / 2`).
+    pub lint_int_div: bool,
+    /// Stop at the first error, and treat uses of undeclared names as errors
+    /// rather than warnings. Meant for CI, where one failure is enough.
+    pub fail_fast: bool,
+    /// Type of unannotated integer literals (and so of the `let`s they
+    /// initialise): `I32` by default, or `I64` for a 64-bit-by-default build.
+    pub default_int_type: ExprType,
+    /// number of `while` bodies around the statement being checked
+    loop_depth: usize,
+    /// number of function bodies around the statement being checked
+    fn_depth: usize,
+    /// result type of the function being checked; `Unknown` outside of one,
+    /// and for functions returning `()`
+    return_type: ExprType,
+    /// whether top-level statements form the implicit `main`, i.e. the
+    /// program does not define its own
+    top_level_is_main: bool,
+    /// every declaration seen, in source order
+    pub symbols: Vec<Symbol>,
+}
+
+impl Default for SemanticAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SemanticAnalyzer {
     pub fn new() -> Self {
         Self {
-            variables: HashSet::new(),
-            functions: HashSet::new(),
+            scopes: vec![HashMap::new()],
+            functions: HashMap::from([(PRINT_BUILTIN.to_string(), (vec![ExprType::I32], ExprType::Unknown))]),
+            called: HashSet::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            lint_int_div: false,
+            fail_fast: false,
+            default_int_type: ExprType::I32,
+            loop_depth: 0,
+            fn_depth: 0,
+            return_type: ExprType::Unknown,
+            top_level_is_main: true,
+            symbols: Vec::new(),
         }
     }
 
-    pub fn analyze(&mut self, stmts: &[Stmt]) {
+    /// Check `stmts`, filling in the type of each unannotated `let` whose
+    /// initializer has a known type.
+    pub fn analyze(&mut self, stmts: &mut [Stmt]) -> Result<(), Vec<Diagnostic>> {
+        // register top-level functions first, so they can be called above
+        // their definition and from each other
+        for stmt in stmts.iter() {
+            if let StmtKind::Function { name, params, ret_type, .. } = &stmt.kind {
+                self.functions.insert(name.clone(), signature_types(params, *ret_type));
+                if name == "main" {
+                    self.top_level_is_main = false;
+                }
+            }
+        }
+        let mut finished = true;
         for stmt in stmts {
+            // a top-level `return` is reported as ReturnOutsideFunction
+            if !self.top_level_is_main && !matches!(stmt.kind, StmtKind::Function { .. } | StmtKind::Return(_)) {
+                self.error(
+                    DiagnosticKind::StatementOutsideFunction,
+                    "statement outside of a function; this program defines its own `main`",
+                    stmt.span,
+                );
+            }
             self.visit_stmt(stmt);
+            if self.fail_fast && !self.errors.is_empty() {
+                finished = false;
+                break;
+            }
+        }
+        // a program cut short by fail_fast would report every name used
+        // only in the statements that were skipped
+        if finished {
+            let top_level = std::mem::take(&mut self.scopes[0]);
+            self.report_unused_variables(top_level);
+            self.report_unused_functions();
+        }
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors.clone())
         }
     }
 
-    fn visit_stmt(&mut self, stmt: &Stmt) {
-        match stmt {
-            Stmt::Let { name, expr } => {
-                self.visit_expr(expr);
-                self.variables.insert(name.clone());
+    fn error(&mut self, kind: DiagnosticKind, message: impl Into<String>, span: Span) {
+        // with fail_fast only the first error is kept; the rest of the current
+        // statement is still walked but its findings are dropped
+        if self.fail_fast && !self.errors.is_empty() {
+            return;
+        }
+        self.errors.push(Diagnostic { kind, message: message.into(), span });
+    }
+
+    /// Use of a name that has not been declared: a warning, or an error under
+    /// `fail_fast`.
+    fn undeclared(&mut self, kind: DiagnosticKind, message: String, span: Span) {
+        if self.fail_fast {
+            self.error(kind, message, span);
+        } else {
+            self.warning(kind, message, span);
+        }
+    }
+
+    fn warning(&mut self, kind: DiagnosticKind, message: impl Into<String>, span: Span) {
+        self.warnings.push(Diagnostic { kind, message: message.into(), span });
+    }
+
+    /// Record a declaration in `symbols`, returning its index there.
+    fn declare(&mut self, name: &str, kind: SymbolKind, ty: String, span: Span) -> usize {
+        let depth = self.scopes.len() - 1;
+        self.symbols.push(Symbol { name: name.to_string(), kind, ty, depth, span });
+        self.symbols.len() - 1
+    }
+
+    /// Bind `name` (declared as `symbols[symbol]`) in the innermost scope,
+    /// shadowing any outer binding.
+    fn bind(&mut self, name: &str, ty: ExprType, symbol: usize) {
+        let binding = Binding { ty, symbol, used: false };
+        self.scopes.last_mut().expect("the top-level scope is never popped").insert(name.to_string(), binding);
+    }
+
+    /// Type of the innermost variable called `name`, if one is in scope.
+    fn lookup(&self, name: &str) -> Option<ExprType> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).map(|b| b.ty)
+    }
+
+    /// Like `lookup`, but for a read of the variable: marks it as used.
+    fn read_var(&mut self, name: &str) -> Option<ExprType> {
+        let binding = self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name))?;
+        binding.used = true;
+        Some(binding.ty)
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn pop_scope(&mut self) {
+        if let Some(scope) = self.scopes.pop() {
+            self.report_unused_variables(scope);
+        }
+    }
+
+    /// Warn about the `let`s in `scope` that are never read. Assigning to a
+    /// variable does not count as using it. Names starting with `_` are
+    /// exempt, and so are parameters, which a caller has to pass anyway.
+    fn report_unused_variables(&mut self, scope: HashMap<String, Binding>) {
+        let mut unused: Vec<&Symbol> = scope
+            .values()
+            .filter(|b| !b.used)
+            .map(|b| &self.symbols[b.symbol])
+            .filter(|s| s.kind == SymbolKind::Variable && !s.name.starts_with('_'))
+            .collect();
+        unused.sort_by_key(|s| (s.span.start_line, s.span.start_col));
+        let warnings: Vec<Diagnostic> = unused
+            .into_iter()
+            .map(|s| Diagnostic {
+                kind: DiagnosticKind::UnusedVariable,
+                message: format!("variable `{}` is never used", s.name),
+                span: s.span,
+            })
+            .collect();
+        self.warnings.extend(warnings);
+    }
+
+    /// Warn about functions that are never called. `main` is exempt, as the
+    /// program calls it.
+    fn report_unused_functions(&mut self) {
+        let warnings: Vec<Diagnostic> = self
+            .symbols
+            .iter()
+            .filter(|s| s.kind == SymbolKind::Function && s.name != "main" && !s.name.starts_with('_'))
+            .filter(|s| !self.called.contains(&s.name))
+            .map(|s| Diagnostic {
+                kind: DiagnosticKind::UnusedFunction,
+                message: format!("function `{}` is never called", s.name),
+                span: s.span,
+            })
+            .collect();
+        self.warnings.extend(warnings);
+    }
+
+    fn visit_block(&mut self, stmts: &mut [Stmt]) {
+        self.push_scope();
+        for s in stmts {
+            self.visit_stmt(s);
+        }
+        self.pop_scope();
+    }
+
+    fn visit_stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::VarDecl { name, var_type, value } => {
+                let ty = match var_type.map(|t| (t, ExprType::from_type(t))) {
+                    Some((_, Some(declared))) => {
+                        let found = self.visit_expr_as(value, declared);
+                        if found != declared && found != ExprType::Unknown {
+                            self.error(
+                                DiagnosticKind::TypeMismatch,
+                                format!("`{}` is declared as {} but initialised with {}", name, declared, found),
+                                value.span,
+                            );
+                        }
+                        declared
+                    }
+                    Some((annotation, None)) => {
+                        self.error(
+                            DiagnosticKind::TypeMismatch,
+                            format!("`{}` cannot be declared as {}", name, annotation),
+                            stmt.span,
+                        );
+                        self.visit_expr(value);
+                        ExprType::Unknown
+                    }
+                    None => {
+                        // infer from the initializer and record it for codegen
+                        let reported = self.errors.len() + self.warnings.len();
+                        let found = self.visit_expr(value);
+                        *var_type = found.to_type();
+                        // an initializer that was already reported on (an
+                        // undeclared name, say) does not need a second diagnostic
+                        if found == ExprType::Unknown && self.errors.len() + self.warnings.len() == reported {
+                            self.error(
+                                DiagnosticKind::CannotInferType,
+                                format!("cannot infer the type of `{}` from its initializer", name),
+                                stmt.span,
+                            );
+                        }
+                        found
+                    }
+                };
+                let symbol = self.declare(name, SymbolKind::Variable, ty.to_string(), stmt.span);
+                self.bind(name, ty, symbol);
             }
-            Stmt::If { cond, then_block, else_block } => {
-                self.visit_expr(cond);
-                for s in then_block {
-                    self.visit_stmt(s);
+            StmtKind::VarDeclTuple { names, value } => {
+                let types = match &mut value.kind {
+                    ExprKind::Tuple(elems) if elems.len() == names.len() => {
+                        elems.iter_mut().map(|e| self.visit_expr(e)).collect()
+                    }
+                    ExprKind::Tuple(elems) => {
+                        self.error(
+                            DiagnosticKind::TypeMismatch,
+                            format!(
+                                "pattern binds {} names but the tuple has {} elements",
+                                names.len(),
+                                elems.len()
+                            ),
+                            value.span,
+                        );
+                        for e in elems {
+                            self.visit_expr(e);
+                        }
+                        vec![ExprType::Unknown; names.len()]
+                    }
+                    _ => {
+                        let found = self.visit_expr(value);
+                        self.error(
+                            DiagnosticKind::TypeMismatch,
+                            format!("expected a tuple of {} elements, found {}", names.len(), found),
+                            value.span,
+                        );
+                        vec![ExprType::Unknown; names.len()]
+                    }
+                };
+                for (name, ty) in names.iter().zip(types) {
+                    let symbol = self.declare(name, SymbolKind::Variable, ty.to_string(), stmt.span);
+                    self.bind(name, ty, symbol);
                 }
-                if let Some(block) = else_block {
-                    for s in block {
-                        self.visit_stmt(s);
+            }
+            StmtKind::Assignment { name, value } => match self.lookup(name) {
+                Some(declared) => {
+                    let found = self.visit_expr_as(value, declared);
+                    if found != declared && found != ExprType::Unknown && declared != ExprType::Unknown {
+                        self.error(
+                            DiagnosticKind::TypeMismatch,
+                            format!("`{}` is declared as {} but assigned {}", name, declared, found),
+                            value.span,
+                        );
                     }
                 }
+                None => {
+                    self.visit_expr(value);
+                    self.undeclared(
+                        DiagnosticKind::UndeclaredVariable,
+                        format!("variable `{}` assigned before declaration", name),
+                        stmt.span,
+                    );
+                }
+            },
+            StmtKind::IfStmt { condition, then_branch, else_branch } => {
+                self.visit_condition(condition, "if");
+                self.visit_block(then_branch);
+                if let Some(block) = else_branch {
+                    self.visit_block(block);
+                }
             }
-            Stmt::While { cond, body } => {
-                self.visit_expr(cond);
-                for s in body {
+            StmtKind::While { condition, body } => {
+                self.visit_condition(condition, "while");
+                self.loop_depth += 1;
+                self.visit_block(body);
+                self.loop_depth -= 1;
+            }
+            StmtKind::DoWhile { body, condition } => {
+                self.loop_depth += 1;
+                self.visit_block(body);
+                self.loop_depth -= 1;
+                self.visit_condition(condition, "do-while");
+            }
+            StmtKind::Function { name, params, ret_type, body } => {
+                let param_types: Vec<String> = params.iter().map(|(_, t)| t.to_string()).collect();
+                let declared_ret = *ret_type;
+                let ret_type = ret_type.unwrap_or(Type::I32);
+                let signature = format!("fn({}) -> {}", param_types.join(", "), ret_type);
+                self.declare(name, SymbolKind::Function, signature, stmt.span);
+                let old_depth = std::mem::take(&mut self.loop_depth);
+                self.fn_depth += 1;
+                let (param_exprs, result) = signature_types(params, declared_ret);
+                let old_return = std::mem::replace(&mut self.return_type, result);
+                // the body sees the globals but not the locals of whatever
+                // function or block encloses it
+                let enclosing = self.scopes.split_off(1);
+                // parameters share a scope with the body's own `let`s
+                self.push_scope();
+                for ((p, t), ty) in params.iter().zip(&param_exprs) {
+                    if *t == Type::Unit {
+                        self.error(DiagnosticKind::TypeMismatch, format!("parameter `{}` cannot have type {}", p, t), stmt.span);
+                    }
+                    let symbol = self.declare(p, SymbolKind::Parameter, t.to_string(), stmt.span);
+                    self.bind(p, *ty, symbol);
+                }
+                self.functions.insert(name.clone(), (param_exprs, result));
+                for s in body.iter_mut() {
                     self.visit_stmt(s);
                 }
+                self.pop_scope();
+                self.scopes.extend(enclosing);
+                self.loop_depth = old_depth;
+                self.fn_depth -= 1;
+                self.return_type = old_return;
+                // only a declared result is enforced; unannotated functions
+                // still return 0 when they fall off the end
+                if let Some(ty) = declared_ret
+                    && ty != Type::Unit
+                    && !always_returns(body)
+                {
+                    self.error(
+                        DiagnosticKind::MissingReturn,
+                        format!("function `{}` must return {} on every path", name, ty),
+                        stmt.span,
+                    );
+                }
             }
-            Stmt::Function { name, params, body } => {
-                self.functions.insert(name.clone());
-                let old_vars = self.variables.clone();
-                for p in params {
-                    self.variables.insert(p.clone());
+            // A top-level `return` is allowed: it ends the program, and its
+            // value becomes the exit value (the result of the implicit `main`).
+            // With a user-defined `main` there is no implicit one to return from.
+            StmtKind::Return(expr) => {
+                if self.fn_depth == 0 && !self.top_level_is_main {
+                    self.error(
+                        DiagnosticKind::ReturnOutsideFunction,
+                        "`return` outside of a function; this program defines its own `main`",
+                        stmt.span,
+                    );
                 }
-                for s in body {
-                    self.visit_stmt(s);
+                if let Some(expr) = expr {
+                    let expected = self.return_type;
+                    let found = self.visit_expr_as(expr, expected);
+                    if found != expected && found != ExprType::Unknown && expected != ExprType::Unknown {
+                        self.error(
+                            DiagnosticKind::TypeMismatch,
+                            format!("the function returns {} but this value is {}", expected, found),
+                            expr.span,
+                        );
+                    }
                 }
-                self.variables = old_vars;
             }
-            Stmt::Return(expr) => {
-                self.visit_expr(expr);
+            StmtKind::Break | StmtKind::Continue if self.loop_depth == 0 => {
+                let keyword = if matches!(stmt.kind, StmtKind::Break) { "break" } else { "continue" };
+                self.error(DiagnosticKind::OutsideLoop, format!("{} outside of loop", keyword), stmt.span);
             }
-            Stmt::Expr(expr) => {
+            StmtKind::Break | StmtKind::Continue => {}
+            StmtKind::ExprStmt(expr) => {
                 self.visit_expr(expr);
             }
         }
     }
 
-    fn visit_expr(&mut self, expr: &Expr) {
-        match expr {
-            Expr::Number(_) => {}
-            Expr::Var(name) => {
-                if !self.variables.contains(name) {
-                    eprintln!("Warning: variable `{}` used before declaration", name);
+    /// Like `visit_expr`, but a literal takes `expected` as its type when that
+    /// is numeric, so `let x: i64 = 5000000000;` and `x + 1` type as i64. The
+    /// operands of arithmetic are visited with the same expectation.
+    fn visit_expr_as(&mut self, expr: &mut Expr, expected: ExprType) -> ExprType {
+        let expected = Some(expected).filter(|t| t.is_numeric());
+        let ty = match &mut expr.kind {
+            ExprKind::Number(lit) => self.literal_type(*lit, expected, expr.span),
+            ExprKind::Binary { left, operator, right } => self.visit_binary(left, *operator, right, expected, expr.span),
+            _ => return self.visit_expr(expr),
+        };
+        expr.ty = ty.to_type();
+        ty
+    }
+
+    /// Type of `left op right`. A literal operand takes the type of the other
+    /// side; when both are literals they share the wider of their own types,
+    /// so `5000000000 + 1` and `1 + 5000000000` are both i64. `expected` is
+    /// the numeric type an arithmetic result is wanted as, if known.
+    fn visit_binary(&mut self, left: &mut Expr, op: BinOp, right: &mut Expr, expected: Option<ExprType>, span: Span) -> ExprType {
+        let expected = expected.filter(|_| matches!(op, BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod));
+        let visit = |this: &mut Self, e: &mut Expr| match expected {
+            Some(ty) => this.visit_expr_as(e, ty),
+            None => this.visit_expr(e),
+        };
+        let (lt, rt) = match (&left.kind, &right.kind) {
+            (ExprKind::Number(l), ExprKind::Number(r)) => {
+                let ty = expected.or_else(|| self.literal_pair_type(*l, *r)).unwrap_or(ExprType::Unknown);
+                (self.visit_expr_as(left, ty), self.visit_expr_as(right, ty))
+            }
+            (ExprKind::Number(_), _) => {
+                let rt = visit(self, right);
+                (self.visit_expr_as(left, rt), rt)
+            }
+            _ => {
+                let lt = visit(self, left);
+                (lt, self.visit_expr_as(right, lt))
+            }
+        };
+        let ty = self.binary_type(op, (lt, left.span), (rt, right.span));
+        if self.lint_int_div && op == BinOp::Div {
+            self.check_int_div(left, right, ty, span);
+        }
+        ty
+    }
+
+    /// The numeric type two literals combine at: i64 if either needs it,
+    /// f64 if either is a float. `None` unless both are numbers.
+    fn literal_pair_type(&self, left: Literal, right: Literal) -> Option<ExprType> {
+        match (self.natural_literal_type(left), self.natural_literal_type(right)) {
+            (l, r) if l == r => Some(l).filter(|t| t.is_numeric()),
+            (ExprType::F64, t) | (t, ExprType::F64) if t.is_numeric() => Some(ExprType::F64),
+            (ExprType::I64, t) | (t, ExprType::I64) if t.is_numeric() => Some(ExprType::I64),
+            _ => None,
+        }
+    }
+
+    /// Type of a literal with nothing around it to say otherwise.
+    fn natural_literal_type(&self, lit: Literal) -> ExprType {
+        match lit {
+            Literal::Float(_) => ExprType::F64,
+            Literal::Bool(_) => ExprType::Bool,
+            Literal::Int(n) if self.default_int_type == ExprType::I32 && i32::try_from(n).is_ok() => ExprType::I32,
+            Literal::Int(_) => ExprType::I64,
+        }
+    }
+
+    /// Type of a numeric literal, range-checked against `expected`. Without a
+    /// numeric context an integer literal has `default_int_type`, widening to
+    /// i64 when it does not fit.
+    fn literal_type(&mut self, lit: Literal, expected: Option<ExprType>, span: Span) -> ExprType {
+        let ty = match (lit, expected) {
+            (Literal::Float(_), Some(ty @ (ExprType::I32 | ExprType::I64))) => {
+                self.error(
+                    DiagnosticKind::LiteralOutOfRange,
+                    format!("float literal {} cannot be used as {}", lit, ty),
+                    span,
+                );
+                return ty;
+            }
+            (Literal::Float(_), _) => return ExprType::F64,
+            (Literal::Bool(_), _) => return ExprType::Bool,
+            (Literal::Int(_), Some(ty)) => ty,
+            (Literal::Int(_), None) => self.natural_literal_type(lit),
+        };
+        let fits = match (lit, ty) {
+            (Literal::Int(n), ExprType::I32) => i32::try_from(n).is_ok(),
+            (Literal::Int(n), ExprType::I64) => i64::try_from(n).is_ok(),
+            _ => true,
+        };
+        if !fits {
+            self.error(DiagnosticKind::LiteralOutOfRange, format!("literal {} is out of range for {}", lit, ty), span);
+        }
+        ty
+    }
+
+    /// Type of `expr`, which is also recorded in `expr.ty` for the later stages.
+    fn visit_expr(&mut self, expr: &mut Expr) -> ExprType {
+        let ty = self.expr_type(expr);
+        expr.ty = ty.to_type();
+        ty
+    }
+
+    fn expr_type(&mut self, expr: &mut Expr) -> ExprType {
+        match &mut expr.kind {
+            ExprKind::Number(lit) => self.literal_type(*lit, None, expr.span),
+            ExprKind::Identifier(name) => {
+                if let Some(ty) = self.read_var(name) {
+                    return ty;
+                }
+                if self.functions.contains_key(name) {
+                    // functions are not first-class values (yet)
+                    self.error(
+                        DiagnosticKind::FunctionAsValue,
+                        format!("function `{}` cannot be used as a value; call it as `{}(...)`", name, name),
+                        expr.span,
+                    );
+                } else {
+                    self.undeclared(
+                        DiagnosticKind::UndeclaredVariable,
+                        format!("variable `{}` used before declaration", name),
+                        expr.span,
+                    );
                 }
+                ExprType::Unknown
             }
-            Expr::Binary { left, right, .. } => {
-                self.visit_expr(left);
-                self.visit_expr(right);
+            ExprKind::Unary { op, operand } => {
+                let ty = self.visit_expr(operand);
+                let (expected, ok, result) = match op {
+                    UnaryOp::Neg => ("numeric", ty.is_numeric(), ty),
+                    UnaryOp::Not => ("bool", ty == ExprType::Bool, ExprType::Bool),
+                };
+                if !ok && ty != ExprType::Unknown {
+                    self.error(
+                        DiagnosticKind::TypeMismatch,
+                        format!("operator `{}` expects a {} operand, found {}", op, expected, ty),
+                        operand.span,
+                    );
+                }
+                result
             }
-            Expr::Call { name, args } => {
-                if !self.functions.contains(name) {
-                    eprintln!("Warning: function `{}` called before declaration", name);
+            ExprKind::Binary { left, operator, right } => self.visit_binary(left, *operator, right, None, expr.span),
+            ExprKind::Call { name, args } => {
+                self.called.insert(name.clone());
+                let signature = self.functions.get(name).cloned();
+                if signature.is_none() {
+                    self.undeclared(
+                        DiagnosticKind::UndeclaredFunction,
+                        format!("call to undeclared function `{}`", name),
+                        expr.span,
+                    );
                 }
-                for arg in args {
-                    self.visit_expr(arg);
+                if let Some((param_types, _)) = &signature
+                    && param_types.len() != args.len()
+                {
+                    self.error(
+                        DiagnosticKind::WrongArgumentCount,
+                        format!("function `{}` takes {} argument(s) but {} were supplied", name, param_types.len(), args.len()),
+                        expr.span,
+                    );
+                }
+                let (param_types, result) = signature.unwrap_or((Vec::new(), ExprType::Unknown));
+                for (i, arg) in args.iter_mut().enumerate() {
+                    let expected = param_types.get(i).copied().unwrap_or(ExprType::Unknown);
+                    let found = self.visit_expr_as(arg, expected);
+                    if found != expected && found != ExprType::Unknown && expected != ExprType::Unknown {
+                        self.error(
+                            DiagnosticKind::TypeMismatch,
+                            format!("argument {} of `{}` should be {}, found {}", i + 1, name, expected, found),
+                            arg.span,
+                        );
+                    }
                 }
+                result
+            }
+            ExprKind::Tuple(elems) => {
+                self.error(
+                    DiagnosticKind::TypeMismatch,
+                    "tuples can only be used to initialise a destructuring `let`",
+                    expr.span,
+                );
+                for e in elems {
+                    self.visit_expr(e);
+                }
+                ExprType::Unknown
+            }
+            ExprKind::Field { base, name } => {
+                self.visit_expr(base);
+                self.error(
+                    DiagnosticKind::TypeMismatch,
+                    format!("field access (`.{}`) is not supported yet", name),
+                    expr.span,
+                );
+                ExprType::Unknown
+            }
+        }
+    }
+
+    /// Check the condition of an `if` or a loop. Integers are tested against
+    /// zero, so a condition may be a bool or an integer, but not an f64.
+    fn visit_condition(&mut self, condition: &mut Expr, keyword: &str) {
+        let found = self.visit_expr(condition);
+        if found == ExprType::F64 {
+            self.error(
+                DiagnosticKind::TypeMismatch,
+                format!("`{}` condition must be a bool or an integer, found {}", keyword, found),
+                condition.span,
+            );
+        }
+    }
+
+    /// Result type of `left op right`, reporting operands of the wrong type.
+    /// Arithmetic and ordering comparisons need numbers, `&&`/`||` need
+    /// booleans, and all but `&&`/`||` need both operands to have the same
+    /// type, as there are no implicit conversions. Arithmetic produces the
+    /// operand type; everything else produces `Bool`.
+    fn binary_type(&mut self, op: BinOp, left: (ExprType, Span), right: (ExprType, Span)) -> ExprType {
+        let (expected, ok): (&str, fn(ExprType) -> bool) = match op {
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod => ("numeric", ExprType::is_numeric),
+            BinOp::Gt | BinOp::Lt | BinOp::Ge | BinOp::Le => ("numeric", ExprType::is_numeric),
+            BinOp::Eq | BinOp::Neq => ("comparable", |_| true),
+            BinOp::And | BinOp::Or => ("bool", |ty| ty == ExprType::Bool),
+        };
+        let mut valid = true;
+        for (ty, span) in [left, right] {
+            if !ok(ty) && ty != ExprType::Unknown {
+                valid = false;
+                self.error(
+                    DiagnosticKind::TypeMismatch,
+                    format!("operator `{}` expects {} operands, found {}", op, expected, ty),
+                    span,
+                );
+            }
+        }
+        let known = left.0 != ExprType::Unknown && right.0 != ExprType::Unknown;
+        if valid && known && left.0 != right.0 {
+            self.error(
+                DiagnosticKind::TypeMismatch,
+                format!("operator `{}` cannot combine {} and {}", op, left.0, right.0),
+                left.1.to(right.1),
+            );
+        }
+        match op {
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod if valid => {
+                if left.0 == ExprType::Unknown { right.0 } else { left.0 }
+            }
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod => ExprType::Unknown,
+            _ => ExprType::Bool,
+        }
+    }
+
+    /// `--lint-int-div`: integer division silently drops the remainder, which
+    /// surprises learners. Warn when both operands are integer constants that
+    /// don't divide evenly. Where the division has type f64 (`let h: f64 =
+    /// 7 / 2;`) the literals are floats and nothing is dropped, but the result
+    /// still differs from what the same line gives as an integer, so that is
+    /// pointed out too.
+    fn check_int_div(&mut self, left: &Expr, right: &Expr, ty: ExprType, span: Span) {
+        if let (Some(a), Some(b)) = (const_int(left), const_int(right))
+            && b != 0
+            && a % b != 0
+        {
+            let message = if ty == ExprType::F64 {
+                format!("{} / {} divides as f64 here, giving {} rather than the integer {}", a, b, a as f64 / b as f64, a / b)
+            } else {
+                format!("integer division {} / {} truncates to {} (remainder {} is discarded)", a, b, a / b, a % b)
+            };
+            self.warning(DiagnosticKind::TruncatingDivision, message, span);
+        }
+    }
+}
+
+/// Parameter and result types of a function as the analyzer tracks them.
+/// `()` has no values, so it is `Unknown` here.
+fn signature_types(params: &[(String, Type)], ret_type: Option<Type>) -> (Vec<ExprType>, ExprType) {
+    let value_type = |ty: Type| ExprType::from_type(ty).unwrap_or(ExprType::Unknown);
+    let params = params.iter().map(|(_, ty)| value_type(*ty)).collect();
+    (params, value_type(ret_type.unwrap_or(Type::I32)))
+}
+
+/// Whether running `stmts` always ends in a `return`: one of them returns, or
+/// is an `if` whose branches both do, or a loop that can never be left.
+fn always_returns(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Return(_) => true,
+        StmtKind::IfStmt { then_branch, else_branch: Some(else_branch), .. } => {
+            always_returns(then_branch) && always_returns(else_branch)
+        }
+        StmtKind::While { condition, body } => {
+            const_int(condition).is_some_and(|c| c != 0) && !breaks_out(body)
+        }
+        StmtKind::DoWhile { body, condition } => {
+            always_returns(body) || (const_int(condition).is_some_and(|c| c != 0) && !breaks_out(body))
+        }
+        _ => false,
+    })
+}
+
+/// Whether `stmts` (a loop body) contain a `break` for that loop.
+fn breaks_out(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Break => true,
+        StmtKind::IfStmt { then_branch, else_branch, .. } => {
+            breaks_out(then_branch) || else_branch.as_deref().is_some_and(breaks_out)
+        }
+        _ => false,
+    })
+}
+
+/// Value of an integer expression built only from literals, if it has one.
+/// Comparisons and logical operators fold to 0 or 1.
+pub(crate) fn const_int(expr: &Expr) -> Option<i32> {
+    match &expr.kind {
+        ExprKind::Number(Literal::Int(n)) => i32::try_from(*n).ok(),
+        ExprKind::Number(Literal::Bool(b)) => Some(*b as i32),
+        ExprKind::Unary { op: UnaryOp::Neg, operand } => const_int(operand)?.checked_neg(),
+        ExprKind::Unary { op: UnaryOp::Not, operand } => Some((const_int(operand)? == 0) as i32),
+        ExprKind::Binary { left, operator, right } => {
+            let (a, b) = (const_int(left)?, const_int(right)?);
+            match operator {
+                BinOp::Add => a.checked_add(b),
+                BinOp::Sub => a.checked_sub(b),
+                BinOp::Mul => a.checked_mul(b),
+                BinOp::Div => a.checked_div(b),
+                BinOp::Mod => a.checked_rem(b),
+                BinOp::Gt => Some((a > b) as i32),
+                BinOp::Lt => Some((a < b) as i32),
+                BinOp::Eq => Some((a == b) as i32),
+                BinOp::Neq => Some((a != b) as i32),
+                BinOp::Ge => Some((a >= b) as i32),
+                BinOp::Le => Some((a <= b) as i32),
+                BinOp::And => Some((a != 0 && b != 0) as i32),
+                BinOp::Or => Some((a != 0 || b != 0) as i32),
             }
         }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn parse(src: &str) -> Program {
+        Parser::new(Lexer::new(src.to_string()).tokenize().unwrap()).parse().unwrap()
+    }
+
+    /// Analyze `src`, returning the error codes reported.
+    fn error_codes(src: &str) -> Vec<&'static str> {
+        let mut program = parse(src);
+        match SemanticAnalyzer::new().analyze(&mut program.statements) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.kind.code()).collect(),
+        }
+    }
+
+    /// The type inferred for the first `let` of `src`.
+    fn inferred_type(src: &str) -> Option<Type> {
+        let mut program = parse(src);
+        SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        match &program.statements[0].kind {
+            StmtKind::VarDecl { var_type, .. } => *var_type,
+            other => panic!("expected a let, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn function_names_are_not_values() {
+        let mut program = parse("fn add(a: i32, b: i32) -> i32 { return a + b; } let f = add;");
+        let errors = SemanticAnalyzer::new().analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, DiagnosticKind::FunctionAsValue);
+        assert_eq!(errors[0].message, "function `add` cannot be used as a value; call it as `add(...)`");
+    }
+
+    #[test]
+    fn literal_operands_widen_symmetrically() {
+        assert_eq!(inferred_type("let x = 5000000000 + 1;"), Some(Type::I64));
+        assert_eq!(inferred_type("let x = 1 + 5000000000;"), Some(Type::I64));
+        assert_eq!(inferred_type("let x = 1 + 2;"), Some(Type::I32));
+        assert_eq!(inferred_type("let x = 1.5 + 2;"), Some(Type::F64));
+        assert_eq!(inferred_type("let x = 1 < 5000000000;"), Some(Type::Bool));
+        assert_eq!(error_codes("let x: i64 = 5000000000 + 1;"), Vec::<&str>::new());
+        assert_eq!(error_codes("let x: i64 = 1 + 2 * 3;"), Vec::<&str>::new());
+        assert_eq!(error_codes("let y: i32 = 2; let x = 5000000000 + y;"), vec!["E005"]);
+    }
+
+    #[test]
+    fn function_bodies_only_see_globals_and_their_parameters() {
+        let src = "let g = 1; fn outer(p: i32) { let local = 2; fn inner() { return local + p; } return g + local + inner(); }";
+        let mut program = parse(src);
+        let mut sem = SemanticAnalyzer::new();
+        sem.fail_fast = true;
+        let errors = sem.analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors[0].kind, DiagnosticKind::UndeclaredVariable);
+        assert!(errors[0].message.contains("`local`"));
+
+        let src = "if 1 < 2 { let hidden = 3; fn f() { return hidden; } print(f()); }";
+        let mut program = parse(src);
+        let mut sem = SemanticAnalyzer::new();
+        sem.fail_fast = true;
+        assert!(sem.analyze(&mut program.statements).is_err());
+
+        assert_eq!(error_codes("let g = 1; fn f(p: i32) { return g + p; } print(f(2));"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn loop_jumps_outside_a_loop_are_errors() {
+        assert_eq!(error_codes("let i = 0; while i < 3 { i = i + 1; if i == 2 { break; } continue; }"), Vec::<&str>::new());
+        assert_eq!(error_codes("break; if true { continue; }"), vec!["E007", "E007"]);
+        assert_eq!(error_codes("while true { fn f() { break; } f(); }"), vec!["E007"]);
+    }
+
+    #[test]
+    fn retired_codes_still_explain() {
+        assert_eq!(DiagnosticKind::from_code("E006"), None);
+        assert!(DiagnosticKind::retired_explanation("e006").unwrap().starts_with("Retired"));
+        assert_eq!(DiagnosticKind::retired_explanation("E999"), None);
+    }
+
+    #[test]
+    fn truncating_integer_division_warns_under_the_lint() {
+        let warnings = |src: &str| {
+            let mut program = parse(src);
+            let mut sem = SemanticAnalyzer::new();
+            sem.lint_int_div = true;
+            sem.analyze(&mut program.statements).unwrap();
+            sem.warnings.iter().map(|w| w.kind.code()).filter(|c| *c == "W001").count()
+        };
+        assert_eq!(warnings("let x = 7 / 2; x;"), 1);
+        assert_eq!(warnings("let x = 6 / 2; x;"), 0);
+        assert_eq!(warnings("let h: f64 = 7 / 2; h;"), 1);
+
+        let mut program = parse("let h: f64 = 7 / 2; h;");
+        let mut sem = SemanticAnalyzer::new();
+        sem.lint_int_div = true;
+        sem.analyze(&mut program.statements).unwrap();
+        assert_eq!(sem.warnings[0].message, "7 / 2 divides as f64 here, giving 3.5 rather than the integer 3");
+    }
+
+    #[test]
+    fn assigned_values_must_match_the_variable_type() {
+        assert_eq!(error_codes("let b: bool = true; b = 5; b;"), ["E002"]);
+        assert_eq!(error_codes("let w: i64 = 1; w = 5000000000; w = w + 1; w;"), Vec::<&str>::new());
+        assert_eq!(error_codes("let f = 1.5; f = 2; f;"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn returned_values_must_match_the_result_type() {
+        let mut program = parse("fn f() -> bool { return 5; } f();");
+        let errors = SemanticAnalyzer::new().analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors[0].kind, DiagnosticKind::TypeMismatch);
+        assert_eq!(errors[0].message, "the function returns bool but this value is i32");
+        assert_eq!(error_codes("fn g() -> f64 { return 2; } g();"), Vec::<&str>::new());
+        // an unannotated function returns an i32
+        assert_eq!(error_codes("fn h() { return 1 < 2; } h();"), ["E002"]);
+    }
+
+    #[test]
+    fn arguments_must_match_the_parameter_types() {
+        assert_eq!(error_codes("fn g(b: bool) -> i32 { if b { return 1; } return 0; } g(7);"), ["E002"]);
+        let mut program = parse("let f = 1.5; print(f);");
+        let errors = SemanticAnalyzer::new().analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors[0].message, "argument 1 of `print` should be i32, found f64");
+        assert_eq!(error_codes("fn w(n: i64) -> i64 { return n; } w(3); print(2 + 2);"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn conditions_must_be_bools_or_integers() {
+        assert_eq!(error_codes("if 1.5 {}"), ["E002"]);
+        assert_eq!(error_codes("let x = 0.5; while x { } do { } while x * 2.0;"), ["E002", "E002"]);
+        assert_eq!(error_codes("let n = 3; if n { } while n > 5 { } if true { }"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn literals_record_the_type_they_resolve_to() {
+        let literal_types = |src: &str| {
+            let mut program = parse(src);
+            SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+            let StmtKind::VarDecl { value, .. } = &program.statements[0].kind else { panic!("expected a let") };
+            let ExprKind::Binary { left, right, .. } = &value.kind else { panic!("expected a binary, found {:?}", value.kind) };
+            (left.ty, right.ty, value.ty)
+        };
+        let i64 = Some(Type::I64);
+        assert_eq!(literal_types("let a: i64 = 3 * 1000000000; a;"), (i64, i64, i64));
+        let f64 = Some(Type::F64);
+        assert_eq!(literal_types("let h: f64 = 7 / 2; h;"), (f64, f64, f64));
+        let i32 = Some(Type::I32);
+        assert_eq!(literal_types("let n = 7 / 2; n;"), (i32, i32, i32));
+    }
+
+    #[test]
+    fn comparisons_and_logical_operators_produce_bool() {
+        assert_eq!(inferred_type("let x = 1 >= 2;"), Some(Type::Bool));
+        assert_eq!(inferred_type("let x = 1 <= 2;"), Some(Type::Bool));
+        assert_eq!(error_codes("let a = 1; let b = 2; let x = a >= b; let y = a <= b; let z = x && y || x;"), Vec::<&str>::new());
+        assert_eq!(error_codes("let z = 1 && 2;"), vec!["E002", "E002"]);
+        assert_eq!(error_codes("let x = 1 < 2; let z = x >= 2;"), vec!["E002"]);
+    }
+
+    #[test]
+    fn undeclared_variables_report_e001() {
+        let mut program = parse("let a = b;");
+        let mut sem = SemanticAnalyzer::new();
+        sem.analyze(&mut program.statements).unwrap();
+        assert_eq!(sem.warnings[0].kind.code(), "E001");
+        let kind = DiagnosticKind::from_code("e001").unwrap();
+        assert_eq!(kind, DiagnosticKind::UndeclaredVariable);
+        assert!(kind.explanation().contains("Declare the variable with `let`"));
+    }
+
+    #[test]
+    fn wide_and_float_literals_type_check_against_their_annotation() {
+        assert_eq!(error_codes("let a: i64 = 5000000000; let b: f64 = 3.25; let c: f64 = 3; let d = a + 1;"), Vec::<&str>::new());
+        assert_eq!(inferred_type("let a: i64 = 5000000000;"), Some(Type::I64));
+        assert_eq!(inferred_type("let b: f64 = 3.25;"), Some(Type::F64));
+        assert_eq!(error_codes("let a: i32 = 5000000000;"), vec!["E005"]);
+        assert_eq!(error_codes("let c: i64 = 5000000000000000000000;"), vec!["E005"]);
+    }
+
+    #[test]
+    fn fail_fast_stops_at_the_first_error() {
+        let program = parse("let a = b; let c = d;");
+        let mut sem = SemanticAnalyzer::new();
+        sem.fail_fast = true;
+        let errors = sem.analyze(&mut program.statements.clone()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, DiagnosticKind::UndeclaredVariable);
+        assert!(errors[0].message.contains("`b`"), "{}", errors[0].message);
+
+        let mut sem = SemanticAnalyzer::new();
+        sem.analyze(&mut program.statements.clone()).unwrap();
+        assert_eq!(sem.warnings.iter().filter(|w| w.kind == DiagnosticKind::UndeclaredVariable).count(), 2);
+    }
+
+    #[test]
+    fn untyped_lets_take_their_initializer_type() {
+        assert_eq!(inferred_type("let x = 1 < 2;"), Some(Type::Bool));
+        assert_eq!(inferred_type("let y = 1 + 2;"), Some(Type::I32));
+        assert_eq!(inferred_type("let a = 1 < 2; let z = a;"), Some(Type::Bool));
+    }
+
+    #[test]
+    fn tuple_lets_need_matching_arity() {
+        assert_eq!(error_codes("let (a, b) = (3, 4); let c = a + b;"), Vec::<&str>::new());
+        assert_eq!(error_codes("let (a, b) = (1, 2, 3);"), vec!["E002"]);
+        assert_eq!(error_codes("let c = (1, 2);"), vec!["E002"]);
+    }
+
+    #[test]
+    fn unary_operators_check_their_operand() {
+        assert_eq!(error_codes("let a = 1; let b = -a; let c = !(a > b);"), Vec::<&str>::new());
+        assert_eq!(error_codes("let a = 1; let b = !a;"), vec!["E002"]);
+        assert_eq!(error_codes("let c = -true;"), vec!["E002"]);
+    }
+
+    #[test]
+    fn top_level_break_reports_where_it_is() {
+        let mut program = parse("break;");
+        let errors = SemanticAnalyzer::new().analyze(&mut program.statements).unwrap_err();
+        let messages: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages, ["1:1: [E007] break outside of loop"]);
+        assert_eq!(error_codes("let x = 1; while x > 0 { break; }"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn symbol_table_lists_every_declaration() {
+        let mut program = parse(
+            "fn add(a: i32, b: i32) -> i32 { return a + b; }
+             let x: i32 = 5; let y: i32 = 10; let z: i32 = add(x, y);
+             if z > 10 { let w = z + 1; }",
+        );
+        let mut sem = SemanticAnalyzer::new();
+        sem.analyze(&mut program.statements).unwrap();
+        let lines: Vec<_> = sem.symbols.iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "function add fn(i32, i32) -> i32 (scope depth 0)",
+                "parameter a i32 (scope depth 1)",
+                "parameter b i32 (scope depth 1)",
+                "variable x i32 (scope depth 0)",
+                "variable y i32 (scope depth 0)",
+                "variable z i32 (scope depth 0)",
+                "variable w i32 (scope depth 1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn block_locals_do_not_leak_out_of_their_block() {
+        let strict_codes = |src: &str| {
+            let mut program = parse(src);
+            let mut sem = SemanticAnalyzer::new();
+            sem.fail_fast = true;
+            match sem.analyze(&mut program.statements) {
+                Ok(()) => Vec::new(),
+                Err(errors) => errors.iter().map(|e| e.kind.code()).collect(),
+            }
+        };
+        assert_eq!(strict_codes("if true { let t = 1; } let u = t;"), ["E001"]);
+        assert_eq!(strict_codes("while false { let w = 1; } w = 2;"), ["E001"]);
+        assert_eq!(strict_codes("let o = 1; if true { o = 2; if true { o = 3; } }"), Vec::<&str>::new());
+
+        // the inner bool shadows the outer i64 only inside the block
+        let mut program = parse("let x: i64 = 1; if true { let x: bool = 1 > 0; let y = !x; } let z = x + 3000000000;");
+        let mut sem = SemanticAnalyzer::new();
+        sem.analyze(&mut program.statements).unwrap();
+        let depths: Vec<_> = sem.symbols.iter().map(|s| (s.name.as_str(), s.depth)).collect();
+        assert_eq!(depths, [("x", 0), ("x", 1), ("y", 1), ("z", 0)]);
+    }
+
+    #[test]
+    fn binary_operands_must_have_matching_types() {
+        assert_eq!(error_codes("let a = 1; let b = 2; let c: i32 = a + b;"), Vec::<&str>::new());
+        let mut program = parse("let x = true + 1;");
+        let errors = SemanticAnalyzer::new().analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("expects numeric operands, found bool"), "{}", errors[0].message);
+        assert_eq!(inferred_type("let a = 1 < 2;"), Some(Type::Bool));
+        assert_eq!(error_codes("let a = 1; let b = 2; let c: i32 = a < b;"), ["E002"]);
+
+        let mut program = parse("let w: i64 = 1; let n: i32 = 2; let s = w < n; let t = w == n;");
+        let errors = SemanticAnalyzer::new().analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].message.contains("cannot combine i64 and i32"), "{}", errors[0].message);
+        assert_eq!(error_codes("let w: i64 = 1; let s = w + 5; let t = 5 < w; let u = true == (1 < 2);"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn calls_must_match_the_function_arity() {
+        let add = "fn add(a: i32, b: i32) -> i32 { return a + b; }";
+        assert_eq!(error_codes(&format!("{} let x = add(1, 2);", add)), Vec::<&str>::new());
+        let mut program = parse(&format!("{} let x = add(1);", add));
+        let errors = SemanticAnalyzer::new().analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors[0].kind, DiagnosticKind::WrongArgumentCount);
+        assert_eq!(errors[0].message, "function `add` takes 2 argument(s) but 1 were supplied");
+        assert_eq!(error_codes(&format!("{} add(1, 2, 3);", add)), ["E008"]);
+
+        let mut program = parse("let y = nope(1);");
+        let mut sem = SemanticAnalyzer::new();
+        sem.analyze(&mut program.statements).unwrap();
+        assert_eq!(sem.warnings[0].kind, DiagnosticKind::UndeclaredFunction);
+    }
+
+    #[test]
+    fn typed_functions_return_on_every_path() {
+        assert_eq!(error_codes("fn sign(n: i32) -> i32 { if n < 0 { return -1; } else { return 1; } }"), Vec::<&str>::new());
+        let mut program = parse("fn sign(n: i32) -> i32 { if n < 0 { return -1; } }");
+        let errors = SemanticAnalyzer::new().analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors[0].kind, DiagnosticKind::MissingReturn);
+        assert_eq!(errors[0].message, "function `sign` must return i32 on every path");
+        assert_eq!(error_codes("fn log(n: i32) -> () { let m = n; }"), Vec::<&str>::new());
+        assert_eq!(error_codes("fn legacy(n: i32) { let m = n; }"), Vec::<&str>::new());
+
+        let chain = "fn f(n: i32) -> i32 { if n < 0 { return 0; } else if n < 5 { return 1; }";
+        assert_eq!(error_codes(&format!("{} else {{ return 2; }} }}", chain)), Vec::<&str>::new());
+        assert_eq!(error_codes(&format!("{} }}", chain)), ["E009"]);
+        assert_eq!(error_codes("fn spin(n: i32) -> bool { while true { if n > 0 { return true; } } }"), Vec::<&str>::new());
+        assert_eq!(error_codes("fn spin(n: i32) -> bool { while true { if n > 0 { break; } } }"), ["E009"]);
+        assert_eq!(error_codes("fn w(n: i32) -> i32 { while n > 0 { return 1; } }"), ["E009"]);
+        assert_eq!(error_codes("fn d(n: i32) -> i64 { do { return 1; } while n > 0; }"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn undeclared_use_is_a_warning_unless_strict() {
+        let mut program = parse("let y = x + 1;");
+        let mut sem = SemanticAnalyzer::new();
+        assert!(sem.analyze(&mut program.statements.clone()).is_ok());
+        let undeclared: Vec<_> = sem.warnings.iter().filter(|w| w.kind == DiagnosticKind::UndeclaredVariable).collect();
+        assert_eq!(undeclared.len(), 1);
+        assert_eq!(undeclared[0].to_string(), "1:9: [E001] variable `x` used before declaration");
+
+        let mut strict = SemanticAnalyzer::new();
+        strict.fail_fast = true;
+        let errors = strict.analyze(&mut program.statements).unwrap_err();
+        assert_eq!(errors[0].kind, DiagnosticKind::UndeclaredVariable);
+    }
+
+    #[test]
+    fn functions_are_known_before_their_definition() {
+        let src = "fn main() -> i32 { return twice(odd(3)); }
+                   fn twice(n: i32) -> i32 { return n * 2; }
+                   fn odd(n: i32) -> i32 { if n == 0 { return 0; } return even(n - 1); }
+                   fn even(n: i32) -> i32 { if n == 0 { return 1; } return odd(n - 1); }";
+        let mut program = parse(src);
+        let mut sem = SemanticAnalyzer::new();
+        sem.fail_fast = true;
+        sem.analyze(&mut program.statements).unwrap();
+        assert!(sem.warnings.is_empty(), "{:?}", sem.warnings);
+        // arity is checked against the later definition too
+        assert_eq!(error_codes("let y = later(1, 2); fn later(x: i32) -> i32 { return x; }"), ["E008"]);
+    }
+
+    #[test]
+    fn return_is_rejected_outside_functions_when_main_exists() {
+        assert_eq!(error_codes("fn main() { return; } return;"), ["E010"]);
+        // the `if` itself is a statement outside of `main`
+        assert_eq!(error_codes("fn main() -> i32 { return 0; } if true { return; }"), ["E012", "E010"]);
+        assert_eq!(
+            error_codes("fn main() -> i32 { if true { while true { return 2; } } return 0; } fn f() { if true { return; } }"),
+            Vec::<&str>::new()
+        );
+        // without a user `main`, top-level code is the implicit one
+        assert_eq!(error_codes("let x = 1; if x > 0 { return x; } return 0;"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn statements_are_rejected_outside_functions_when_main_exists() {
+        assert_eq!(error_codes("let limit = 10; fn main() -> i32 { return 0; }"), ["E012"]);
+        assert_eq!(error_codes("fn main() -> i32 { return 0; } print(1); while true { }"), ["E012", "E012"]);
+        assert_eq!(error_codes("fn f() -> i32 { return 1; } fn main() -> i32 { return f(); }"), Vec::<&str>::new());
+        assert_eq!(error_codes("let limit = 10; print(limit);"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn unused_variables_and_functions_warn() {
+        let warnings = |src: &str| {
+            let mut program = parse(src);
+            let mut sem = SemanticAnalyzer::new();
+            sem.analyze(&mut program.statements).unwrap();
+            sem.warnings
+        };
+        let found = warnings("let used = 1; let unused = 2; unused = 3; return used;");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].kind, DiagnosticKind::UnusedVariable);
+        assert!(found[0].message.contains("`unused`"), "{}", found[0].message);
+
+        let found = warnings("fn main() -> i32 { return helper(1); } fn helper(n: i32) -> i32 { if n > 0 { let t = 1; } return 0; } fn dead() { }");
+        let found: Vec<_> = found.iter().map(|w| (w.kind, w.message.as_str())).collect();
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found.iter().any(|(kind, message)| *kind == DiagnosticKind::UnusedVariable && message.contains("`t`")));
+        assert!(found.iter().any(|(kind, message)| *kind == DiagnosticKind::UnusedFunction && message.contains("`dead`")));
+        assert!(warnings("let _x = 1; fn _f() { }").is_empty());
+    }
+
+    #[test]
+    fn let_types_follow_operators_and_calls() {
+        assert_eq!(inferred_type("let y = 3;"), Some(Type::I32));
+        let mut program = parse("let a = 1; let c = 2; let b = a < c; fn wide() -> i64 { return 1; } let w = wide() + 1;");
+        SemanticAnalyzer::new().analyze(&mut program.statements).unwrap();
+        let types: Vec<_> = program
+            .statements
+            .iter()
+            .filter_map(|stmt| match &stmt.kind {
+                StmtKind::VarDecl { var_type, .. } => Some(*var_type),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(types, [Some(Type::I32), Some(Type::I32), Some(Type::Bool), Some(Type::I64)]);
+        assert_eq!(error_codes("fn log(n: i32) -> () { } let x = log(1);"), ["E011"]);
+        // already reported as undeclared, so no second diagnostic
+        assert_eq!(error_codes("let z = nope;"), Vec::<&str>::new());
     }
 }
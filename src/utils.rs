@@ -1,2 +1,196 @@
 // helper functions
+use crate::ast::{Expr, ExprKind, Program, Span, Stmt, StmtKind};
+use crate::lexer::{Token, TokenKind};
 
+/// Render `message` together with the source line `span` starts on and a
+/// caret underline beneath the offending region:
+///
+/// ```text
+/// error: unknown variable w
+///  --> 3:9
+///   |
+/// 3 | let z = w + 1;
+///   |         ^
+/// ```
+pub fn render_snippet(source: &str, span: Span, message: &str) -> String {
+    render_labeled_snippet(source, span, "error", message)
+}
+
+/// `render_snippet` with a custom header label, e.g. `error[E002]`.
+pub fn render_labeled_snippet(source: &str, span: Span, label: &str, message: &str) -> String {
+    let line_text = source.lines().nth(span.start_line.saturating_sub(1)).unwrap_or("");
+    let line_no = span.start_line.to_string();
+    let gutter = " ".repeat(line_no.len());
+
+    let width = if span.end_line == span.start_line && span.end_col > span.start_col {
+        span.end_col - span.start_col
+    } else {
+        1
+    };
+    let underline = format!("{}{}", " ".repeat(span.start_col.saturating_sub(1)), "^".repeat(width));
+
+    format!(
+        "{}: {}\n{}--> {}\n{} |\n{} | {}\n{} | {}",
+        label, message, gutter, span, gutter, line_no, line_text, gutter, underline
+    )
+}
+
+/// Rebuild a normalised source string from a token stream, for eyeballing
+/// what the lexer saw. Tokens are separated by single spaces and a newline
+/// follows every `;` and `}`. This is not a formatter.
+pub fn tokens_to_source(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    for tok in tokens.iter().filter(|t| t.kind != TokenKind::EOF) {
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push(' ');
+        }
+        out.push_str(&tok.value);
+        if matches!(tok.kind, TokenKind::Semicolon | TokenKind::RBrace) {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Structural equality of two programs, ignoring spans. Meant for testing AST
+/// passes against a hand-built expected tree.
+pub fn ast_eq(a: &Program, b: &Program) -> bool {
+    without_spans(a) == without_spans(b)
+}
+
+/// `None` if the programs are structurally equal (ignoring spans), otherwise
+/// a line diff of their pretty-printed trees: `-` lines are only in
+/// `expected`, `+` lines only in `actual`.
+pub fn ast_diff(expected: &Program, actual: &Program) -> Option<String> {
+    if ast_eq(expected, actual) {
+        return None;
+    }
+    let old = format!("{:#?}", without_spans(expected));
+    let new = format!("{:#?}", without_spans(actual));
+    Some(line_diff(&old.lines().collect::<Vec<_>>(), &new.lines().collect::<Vec<_>>()))
+}
+
+/// Copy of `program` with every span reset to the default.
+pub fn without_spans(program: &Program) -> Program {
+    let mut program = program.clone();
+    program.statements.iter_mut().for_each(clear_stmt_spans);
+    program
+}
+
+fn clear_stmt_spans(stmt: &mut Stmt) {
+    stmt.span = Span::default();
+    match &mut stmt.kind {
+        StmtKind::VarDecl { value, .. }
+        | StmtKind::VarDeclTuple { value, .. }
+        | StmtKind::Assignment { value, .. }
+        | StmtKind::ExprStmt(value)
+        | StmtKind::Return(Some(value)) => clear_expr_spans(value),
+        StmtKind::IfStmt { condition, then_branch, else_branch } => {
+            clear_expr_spans(condition);
+            then_branch.iter_mut().for_each(clear_stmt_spans);
+            else_branch.iter_mut().flatten().for_each(clear_stmt_spans);
+        }
+        StmtKind::While { condition, body } | StmtKind::DoWhile { body, condition } => {
+            clear_expr_spans(condition);
+            body.iter_mut().for_each(clear_stmt_spans);
+        }
+        StmtKind::Function { body, .. } => body.iter_mut().for_each(clear_stmt_spans),
+        StmtKind::Return(None) | StmtKind::Break | StmtKind::Continue => {}
+    }
+}
+
+fn clear_expr_spans(expr: &mut Expr) {
+    expr.span = Span::default();
+    match &mut expr.kind {
+        ExprKind::Number(_) | ExprKind::Identifier(_) => {}
+        ExprKind::Unary { operand, .. } | ExprKind::Field { base: operand, .. } => clear_expr_spans(operand),
+        ExprKind::Binary { left, right, .. } => {
+            clear_expr_spans(left);
+            clear_expr_spans(right);
+        }
+        ExprKind::Call { args: exprs, .. } | ExprKind::Tuple(exprs) => exprs.iter_mut().for_each(clear_expr_spans),
+    }
+}
+
+/// Minimal line diff via longest common subsequence. Unchanged lines are
+/// prefixed with two spaces.
+fn line_diff(old: &[&str], new: &[&str]) -> String {
+    // lcs[i][j]: length of the LCS of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut out = String::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            out += &format!("  {}\n", old[i]);
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out += &format!("- {}\n", old[i]);
+            i += 1;
+        } else {
+            out += &format!("+ {}\n", new[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    /// The program `main` compiles when given no file.
+    const SAMPLE: &str = "
+        fn add(a: i32, b: i32) -> i32 {
+            return a + b;
+        }
+
+        let x: i32 = 5;
+        let y: i32 = 10;
+        let z: i32 = add(x, y);
+        if z > 10 {
+            z = z + 1;
+        } else {
+            z = z - 1;
+        }
+        // while example
+        let i: i32 = 0;
+    ";
+
+    fn kinds(src: &str) -> Vec<TokenKind> {
+        Lexer::new(src.to_string()).tokenize().unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn reconstructed_tokens_relex_to_the_same_kinds() {
+        let tokens = Lexer::new(SAMPLE.to_string()).tokenize().unwrap();
+        let rebuilt = tokens_to_source(&tokens);
+        assert!(rebuilt.starts_with("fn add ( a : i32 , b : i32 ) -> i32 { return a + b ;\n}\n"), "{}", rebuilt);
+        assert_eq!(kinds(&rebuilt), kinds(SAMPLE));
+    }
+
+    fn let_number(name: &str, n: i128) -> Program {
+        let value = Expr::new(ExprKind::Number(crate::ast::Literal::Int(n)), Span::default());
+        let kind = StmtKind::VarDecl { name: name.to_string(), var_type: None, value };
+        Program { statements: vec![Stmt { kind, span: Span::default() }] }
+    }
+
+    #[test]
+    fn folded_program_equals_a_hand_built_tree() {
+        let mut program = crate::parser::Parser::new(Lexer::new("let x = 2 + 3;".to_string()).tokenize().unwrap()).parse().unwrap();
+        crate::optimiser::fold_constants(&mut program);
+        let expected = let_number("x", 5);
+        assert!(ast_eq(&program, &expected), "{}", ast_diff(&expected, &program).unwrap());
+        assert_eq!(ast_diff(&expected, &program), None);
+
+        let diff = ast_diff(&let_number("x", 6), &program).unwrap();
+        assert!(diff.lines().any(|line| line.starts_with('-') && line.contains('6')), "{}", diff);
+        assert!(diff.lines().any(|line| line.starts_with('+') && line.contains('5')), "{}", diff);
+    }
+}